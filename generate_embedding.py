@@ -1,40 +1,108 @@
 import sys
+import io
 import base64
 import json
+import argparse
 import numpy as np
-from transformers import AutoTokenizer, AutoModel
 import torch
+import transformers
+from transformers import AutoTokenizer, AutoModel
+
+# Short model names used in config.yml mapped to their Hugging Face repositories
+MODEL_IDS = {
+    'distilbert-base-uncased': 'distilbert-base-uncased',
+    'bert-base-uncased': 'bert-base-uncased',
+    'codebert': 'microsoft/codebert-base',
+    'gpt-neo': 'EleutherAI/gpt-neo-125M',
+    'CLIP': 'openai/clip-vit-base-patch32',
+    'wav2vec2-base': 'facebook/wav2vec2-base',
+}
+
+def resolve_model_id(model_name):
+    return MODEL_IDS.get(model_name, model_name)
+
+def model_version(model):
+    # The Hub commit hash identifies the exact weights; fall back to the library version
+    commit = getattr(model.config, '_commit_hash', None)
+    return f"{model.name_or_path}@{commit or transformers.__version__}"
+
+def embed_text(decoded_bytes, model_name, model_dir):
+    model_id = resolve_model_id(model_name)
+    # Load pre-trained model and tokenizer for generating embeddings
+    tokenizer = AutoTokenizer.from_pretrained(model_id, cache_dir=model_dir)
+    model = AutoModel.from_pretrained(model_id, cache_dir=model_dir)
+    if tokenizer.pad_token is None:
+        tokenizer.pad_token = tokenizer.eos_token
+    # Convert to string
+    text = decoded_bytes.decode('utf-8', errors='ignore')
+    # Tokenize the text
+    inputs = tokenizer(text, return_tensors='pt', truncation=True, padding=True)
+    # Generate embedding using the model
+    with torch.no_grad():
+        outputs = model(**inputs)
+    # Use the mean pooling of the token embeddings to get a single embedding vector per chunk
+    embeddings = torch.mean(outputs.last_hidden_state, dim=1)
+    return embeddings.detach().numpy().flatten().tolist(), model_version(model)
+
+def embed_clip(decoded_bytes, model_name, model_dir):
+    from transformers import CLIPModel, CLIPProcessor
+    from PIL import Image, UnidentifiedImageError
 
-# Load pre-trained model and tokenizer for generating embeddings
-tokenizer = AutoTokenizer.from_pretrained('distilbert-base-uncased')
-model = AutoModel.from_pretrained('distilbert-base-uncased')
+    model_id = resolve_model_id(model_name)
+    model = CLIPModel.from_pretrained(model_id, cache_dir=model_dir)
+    processor = CLIPProcessor.from_pretrained(model_id, cache_dir=model_dir)
+    with torch.no_grad():
+        try:
+            image = Image.open(io.BytesIO(decoded_bytes)).convert('RGB')
+            features = model.get_image_features(**processor(images=image, return_tensors='pt'))
+        except UnidentifiedImageError:
+            # Later chunks of a large image can't be decoded on their own; CLIP's
+            # text tower keeps them in the same embedding space
+            text = decoded_bytes.decode('utf-8', errors='ignore')
+            inputs = processor(text=[text], return_tensors='pt', truncation=True, padding=True)
+            features = model.get_text_features(**inputs)
+    return features.detach().numpy().flatten().tolist(), model_version(model)
 
-def generate_embedding(input_bytes):
+def embed_audio(decoded_bytes, model_name, model_dir):
+    import soundfile
+    from transformers import Wav2Vec2FeatureExtractor, Wav2Vec2Model
+
+    model_id = resolve_model_id(model_name)
+    extractor = Wav2Vec2FeatureExtractor.from_pretrained(model_id, cache_dir=model_dir)
+    model = Wav2Vec2Model.from_pretrained(model_id, cache_dir=model_dir)
+    samples, sample_rate = soundfile.read(io.BytesIO(decoded_bytes), dtype='float32')
+    if samples.ndim > 1:
+        samples = np.mean(samples, axis=1)
+    inputs = extractor(samples, sampling_rate=sample_rate, return_tensors='pt')
+    with torch.no_grad():
+        outputs = model(**inputs)
+    embeddings = torch.mean(outputs.last_hidden_state, dim=1)
+    return embeddings.detach().numpy().flatten().tolist(), model_version(model)
+
+def generate_embedding(input_bytes, model_name='distilbert-base-uncased', model_dir=None):
+    """Returns the embedding vector and the version of the model that produced it."""
     # Decode the chunk from base64
     decoded_bytes = base64.b64decode(input_bytes)
-    try:
-        # Convert to string
-        text = decoded_bytes.decode('utf-8', errors='ignore')
-        # Tokenize the text
-        inputs = tokenizer(text, return_tensors='pt', truncation=True, padding=True)
-        # Generate embedding using the model
-        with torch.no_grad():
-            outputs = model(**inputs)
-        # Use the mean pooling of the token embeddings to get a single embedding vector per chunk
-        embeddings = torch.mean(outputs.last_hidden_state, dim=1)
-        return embeddings.detach().numpy().flatten().tolist()
-    except UnicodeDecodeError:
-        print("Could not decode bytes as UTF-8. Returning an empty embedding.")
-        return []
+    if model_name == 'CLIP':
+        return embed_clip(decoded_bytes, model_name, model_dir)
+    if model_name.startswith('wav2vec2'):
+        return embed_audio(decoded_bytes, model_name, model_dir)
+    return embed_text(decoded_bytes, model_name, model_dir)
 
 def main():
-    if len(sys.argv) != 2:
-        print("Usage: python generate_embedding.py <base64_encoded_chunk>")
-        sys.exit(1)
+    parser = argparse.ArgumentParser(description='Generate an embedding for a base64 encoded chunk.')
+    parser.add_argument('chunk', nargs='?', help='Base64 encoded chunk; read from stdin when omitted')
+    parser.add_argument('--model', default='distilbert-base-uncased', help='Model name from config.yml')
+    parser.add_argument('--model-dir', default=None, help='Directory used to cache downloaded models')
+    args = parser.parse_args()
 
-    input_data = sys.argv[1]
-    embedding = generate_embedding(input_data)
-    print(json.dumps(embedding))
+    input_data = args.chunk if args.chunk is not None else sys.stdin.read()
+    try:
+        embedding, version = generate_embedding(input_data, args.model, args.model_dir)
+    except Exception as e:
+        print(f"Could not embed chunk with {args.model}: {e}", file=sys.stderr)
+        sys.exit(1)
+    print(json.dumps({'embedding': embedding, 'version': version}))
 
 if __name__ == "__main__":
-    main()
\ No newline at end of file
+    main()
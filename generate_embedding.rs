@@ -1,26 +1,43 @@
-use std::process::Command;
-use base64::{Engine as _, engine::general_purpose};
+use base64::{engine::general_purpose, Engine as _};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[derive(serde::Deserialize)]
+struct EmbedderOutput {
+    embedding: Vec<f32>,
+}
 
 fn generate_embedding(input_bytes: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
-    let decoded_bytes = general_purpose::STANDARD.decode(input_bytes)?;
-    let input_str = String::from_utf8_lossy(&decoded_bytes);
+    // Validate the input before handing it to the Python embedder
+    general_purpose::STANDARD.decode(input_bytes)?;
 
-    let output = Command::new("python3")
+    let mut child = Command::new("python3")
         .arg("generate_embedding.py")
-        .arg(input_str)
-        .output()?;
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or("Embedding process has no stdin")?
+        .write_all(input_bytes.as_bytes())?;
+    let output = child.wait_with_output()?;
 
     if !output.status.success() {
         return Err(Box::new(std::io::Error::new(
             std::io::ErrorKind::Other,
-            format!("Failed to generate embedding: {}", String::from_utf8_lossy(&output.stderr)),
+            format!(
+                "Failed to generate embedding: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
         )));
     }
 
     let output_str = String::from_utf8(output.stdout)?;
-    let embeddings: Vec<f32> = serde_json::from_str(&output_str)?;
+    let output: EmbedderOutput = serde_json::from_str(&output_str)?;
 
-    Ok(embeddings)
+    Ok(output.embedding)
 }
 
 fn main() {
@@ -34,4 +51,4 @@ fn main() {
         Ok(embedding) => println!("{}", serde_json::to_string(&embedding).unwrap()),
         Err(e) => eprintln!("Error: {}", e),
     }
-}
\ No newline at end of file
+}
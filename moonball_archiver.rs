@@ -1,411 +1,7869 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, BufReader, BufWriter, Seek, SeekFrom};
-use zstd::stream::{copy_encode, copy_decode};
-use brotli;
-use lzma;
-use serde::{Serialize, Deserialize};
-use rayon::prelude::*;
+use base32::Alphabet::RFC4648;
 use clap::{App, Arg};
-use std::collections::HashMap;
-use std::sync::Mutex;
-use crypto::aes::{KeySize, cbc_encryptor, cbc_decryptor};
-use crypto::buffer::{RefReadBuffer, RefWriteBuffer, BufferResult};
-use crypto::symmetriccipher::{Encryptor, Decryptor};
-use crypto::sha2::Sha256;
+use crypto::aes::{cbc_decryptor, cbc_encryptor, KeySize};
+use crypto::buffer::{BufferResult, RefReadBuffer, RefWriteBuffer};
+use crypto::digest::Digest;
 use crypto::hmac::Hmac;
 use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256, Sha512};
 use eframe::egui;
-use ndarray::Array1;
-use std::process::Command;
-use std::time::{SystemTime, UNIX_EPOCH};
-use base32::Alphabet::RFC4648;
-use base32;
+use log::{error, info};
 use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_yaml::Value;
+use std::cell::RefCell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use totp_lite::totp_custom;
-use log::{info, error};
+use zstd::stream::copy_decode;
 
-#[derive(Serialize, Deserialize, Debug)]
+const VERSION: &str = "1.0"; // Reported by --version and to MCP clients
+
+// Archive layout: header (magic, format version, flags), the stored chunk data,
+// optional binary sections listed in the index, the JSON index and finally a
+// fixed-size footer pointing back at the index.
+const MAGIC: &[u8; 4] = b"MNBL";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: u64 = 7;
+const FOOTER_LEN: u64 = 8 + 8 + 32; // index offset, index length, md5 hex checksum
+const FLAG_ENCRYPTED_INDEX: u8 = 0b0000_0001;
+const FLAG_SCHEME_MASK: u8 = 0b0000_0110; // Compression scheme, readable without decrypting the index
+const MAX_SNAPSHOT_CHAIN: usize = 64;
+const OTP_ENV_VAR: &str = "MOONBALL_OTP"; // A 2FA code, not a config setting
+
+/// Errors returned by MoonBall operations. Each kind has its own process exit
+/// code so scripts can react to it, see `MoonBallError::exit_code`.
+#[derive(Debug)]
+pub enum MoonBallError {
+    /// Not a MoonBall archive, or its layout is damaged.
+    Format(String),
+    /// Written by a newer format version than this build reads.
+    UnsupportedVersion {
+        archive: String,
+        version: u16,
+    },
+    /// Stored data doesn't match its checksum. `chunk` holds the file name and
+    /// chunk ID when the damaged part is chunk data.
+    ChecksumMismatch {
+        archive: String,
+        part: String,
+        chunk: Option<(String, usize)>,
+    },
+    /// Encrypted data could not be decrypted with the archive key.
+    WrongPassword,
+    /// The archive requires 2FA and the one-time password was missing or wrong.
+    Otp(String),
+    UnsupportedCodec(String),
+    Embedding(String),
+    Config(String),
+    Cancelled,
+    Io(std::io::Error),
+    Other(String),
+}
+
+impl MoonBallError {
+    /// Exit code for the CLI. 1 covers unclassified errors and 2 is left to clap
+    /// for invalid arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MoonBallError::Other(_) => 1,
+            MoonBallError::Format(_) => 3,
+            MoonBallError::UnsupportedVersion { .. } => 4,
+            MoonBallError::ChecksumMismatch { .. } => 5,
+            MoonBallError::WrongPassword => 6,
+            MoonBallError::Otp(_) => 7,
+            MoonBallError::UnsupportedCodec(_) => 8,
+            MoonBallError::Embedding(_) => 9,
+            MoonBallError::Config(_) => 10,
+            MoonBallError::Io(_) => 11,
+            MoonBallError::Cancelled => 130, // As if interrupted by SIGINT
+        }
+    }
+}
+
+impl std::fmt::Display for MoonBallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MoonBallError::Format(message)
+            | MoonBallError::Otp(message)
+            | MoonBallError::Other(message) => write!(f, "{}", message),
+            MoonBallError::UnsupportedVersion { archive, version } => {
+                write!(
+                    f,
+                    "{} uses unsupported format version {} (this build reads up to {})",
+                    archive, version, FORMAT_VERSION
+                )
+            }
+            MoonBallError::ChecksumMismatch {
+                archive,
+                part,
+                chunk,
+            } => {
+                write!(f, "Checksum mismatch in the {} of {}", part, archive)?;
+                match chunk {
+                    Some((file_name, chunk_id)) => {
+                        write!(f, " (chunk {} of {})", chunk_id, file_name)
+                    }
+                    None => Ok(()),
+                }
+            }
+            MoonBallError::WrongPassword => write!(
+                f,
+                "Could not decrypt the archive: wrong key or damaged data"
+            ),
+            MoonBallError::UnsupportedCodec(codec) => write!(f, "Unsupported codec: {}", codec),
+            MoonBallError::Embedding(message) => write!(f, "Embedding failed: {}", message),
+            MoonBallError::Config(message) => write!(f, "Invalid configuration:\n  {}", message),
+            MoonBallError::Cancelled => write!(f, "Operation cancelled"),
+            MoonBallError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for MoonBallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MoonBallError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for MoonBallError {
+    fn from(err: std::io::Error) -> Self {
+        MoonBallError::Io(err)
+    }
+}
+
+impl From<String> for MoonBallError {
+    fn from(message: String) -> Self {
+        MoonBallError::Other(message)
+    }
+}
+
+impl From<&str> for MoonBallError {
+    fn from(message: &str) -> Self {
+        MoonBallError::Other(message.to_string())
+    }
+}
+
+impl From<crypto::symmetriccipher::SymmetricCipherError> for MoonBallError {
+    fn from(err: crypto::symmetriccipher::SymmetricCipherError) -> Self {
+        MoonBallError::Other(format!("Cipher error: {:?}", err))
+    }
+}
+
+/// Library errors without a kind of their own are reported as `Other`.
+macro_rules! other_errors {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for MoonBallError {
+                fn from(err: $error) -> Self {
+                    MoonBallError::Other(err.to_string())
+                }
+            }
+        )*
+    };
+}
+
+other_errors!(
+    serde_json::Error,
+    reqwest::Error,
+    base64::DecodeError,
+    lzma::LzmaError,
+    xz2::stream::Error,
+    rayon::ThreadPoolBuildError,
+    std::array::TryFromSliceError,
+    std::num::ParseIntError,
+    std::time::SystemTimeError,
+    ctrlc::Error,
+    eframe::Error,
+);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct ChunkMetadata {
     file_name: String,
     chunk_id: usize,
     original_size: usize,
     compressed_size: usize,
     compression_algo: String,
+    #[serde(default)]
+    codec_id: u8, // Stable registry ID of the codec, see `CodecRegistry`
     embedding: Option<Vec<f32>>, // Embedding for semantic search
+    #[serde(default)]
+    embedding_model: Option<String>, // Model that produced `embedding`
+    #[serde(default)]
+    embedding_space: Option<u32>, // Entry in `ArchiveMetadata.embedding_spaces`
+    #[serde(default)]
+    embedding_pending: bool, // Archived without an embedding; `--reindex` fills it in
+    hash: String,                // SHA-256 of the uncompressed chunk
+    #[serde(default)]
+    offset: u64, // Position of the stored chunk within the data section
+    #[serde(default)]
+    base_ref: bool, // Chunk data is stored in the base archive chain
+    #[serde(default)]
+    solid: Option<SolidSlice>, // Set when the chunk lives inside a solid block
+    #[serde(default)]
+    dictionary_id: Option<u32>, // zstd dictionary used to compress the stored data
+    #[serde(default)]
+    filters: Vec<Filter>, // Applied in order before compression, inverted in reverse on extraction
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ArchiveMetadata {
-    chunks: Vec<ChunkMetadata>,
-    encryption_enabled: bool,
-    requires_2fa: bool,
-    secret_key: Option<String>, // 2FA Secret Key
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SolidSlice {
+    block_hash: String,
+    offset: usize,
 }
 
-pub struct MoonBallArchive {
-    metadata: ArchiveMetadata,
-    files: Mutex<HashMap<String, Vec<u8>>>, // Cache to store compressed chunks in memory for parallel operations
-    config: Config,
+/// Identity of the model that produced a set of embeddings. Vectors are only
+/// comparable within the same space.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct EmbeddingSpace {
+    id: u32,
+    model: String,
+    version: String, // Model revision reported by the embedder
+    dimension: usize,
+    normalized: bool, // Vectors are L2-normalized, so cosine similarity is a dot product
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Config {
-    preset: String,
-    custom_models: HashMap<String, String>,
-    multi_modal_model: String,
-    fallback_model: String,
-    fallback_provider_settings: FallbackProviderSettings,
-    caching: bool,
-    model_download_path: String,
-    logging_level: String,
-    parallel_threads: String,
-    compression_level: u8,
-    chunk_size: usize,
-    error_handling: String,
-    auto_update_models: bool,
-    scheme: String,
-    preset_configs: HashMap<String, PresetConfig>,
-    semantic_search: SemanticSearchConfig,
-    encryption: EncryptionConfig,
-    two_factor_authentication: TwoFactorAuthenticationConfig,
-    compression_algorithms: CompressionAlgorithmsConfig,
-    logging: LoggingConfig,
+impl EmbeddingSpace {
+    fn matches(&self, embedding: &Embedding) -> bool {
+        self.model == embedding.model
+            && self.version == embedding.version
+            && self.dimension == embedding.vector.len()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct FallbackProviderSettings {
-    ollama: OllamaSettings,
-    openai: OpenAISettings,
+/// An embedding as returned by the embedding script.
+struct Embedding {
+    vector: Vec<f32>,
+    model: String,
+    version: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct OllamaSettings {
-    provider: String,
-    model: String,
-    contextLength: u32,
-    systemMessage: String,
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub file_name: String,
+    pub chunk_id: usize,
+    pub byte_range: std::ops::Range<u64>, // Position of the chunk within the original file
+    pub score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<Snippet>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct OpenAISettings {
-    provider: String,
-    model: String,
-    contextLength: u32,
-    apiKey: String,
-    apiBase: String,
-    systemMessage: String,
+impl SearchHit {
+    fn new(metadata: &ArchiveMetadata, chunk: &ChunkMetadata, score: f32) -> Self {
+        let start = metadata.file_offset(chunk);
+        SearchHit {
+            file_name: chunk.file_name.clone(),
+            chunk_id: chunk.chunk_id,
+            byte_range: start..start + chunk.original_size as u64,
+            score,
+            snippet: None,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PresetConfig {
-    custom_models: HashMap<String, String>,
-    multi_modal: bool,
+#[derive(Serialize, Debug)]
+pub struct FileSummary {
+    pub path: String,
+    pub size: u64,
+    pub chunks: usize,
+    pub codecs: Vec<String>, // Distinct codecs of its chunks, in first-use order
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SemanticSearchConfig {
-    threshold: f32,
+#[derive(Serialize, Debug)]
+struct ArchiveStats {
+    files: usize,
+    chunks: usize,
+    base_chunks: usize, // Stored in the snapshot base chain rather than this archive
+    original_size: u64,
+    stored_size: u64,
+    ratio: f64,
+    scheme: Option<Scheme>,
+    encrypted: bool,
+    requires_2fa: bool,
+    base_archive: Option<String>,
+    embedding_spaces: Vec<EmbeddingSpace>,
+    sections: Vec<(String, u64)>, // Name and stored length
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct EncryptionConfig {
-    algorithm: String,
+/// Decompressed excerpt of a hit with the regions matching the query.
+#[derive(Serialize, Debug)]
+pub struct Snippet {
+    pub text: String,
+    pub highlights: Vec<std::ops::Range<usize>>, // Byte ranges within `text`
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TwoFactorAuthenticationConfig {
-    enabled: bool,
-    issuer: String,
-    algorithm: String,
-    period: u32,
+impl Snippet {
+    /// Renders the snippet with highlights as ANSI bold when `ansi` is set, or
+    /// wrapped in `**` otherwise.
+    pub fn render(&self, ansi: bool) -> String {
+        let (open, close) = if ansi {
+            ("\x1b[1;33m", "\x1b[0m")
+        } else {
+            ("**", "**")
+        };
+        let mut rendered = String::with_capacity(self.text.len());
+        let mut last = 0;
+        for highlight in &self.highlights {
+            rendered.push_str(&self.text[last..highlight.start]);
+            rendered.push_str(open);
+            rendered.push_str(&self.text[highlight.clone()]);
+            rendered.push_str(close);
+            last = highlight.end;
+        }
+        rendered.push_str(&self.text[last..]);
+        rendered
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct CompressionAlgorithmsConfig {
-    brotli: BrotliConfig,
-    lzma: LzmaConfig,
-    zstd: ZstdConfig,
+struct DictionaryMetadata {
+    id: u32,
+    data: String, // Base64 encoded zstd dictionary
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct BrotliConfig {
-    level: u32,
+/// How embeddings are encoded in the embeddings section.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Quantization {
+    F32,
+    F16,
+    Int8, // One f32 scale per vector followed by its i8 components
+    Pq,   // Product quantization: a shared codebook and one byte per subvector
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct LzmaConfig {
-    preset: String,
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct PqParams {
+    subvectors: usize,
+    centroids: usize,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ZstdConfig {
-    level: i32,
+/// A binary section stored between the chunk data and the index.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SectionMetadata {
+    name: String,
+    offset: u64, // Absolute position in the archive file
+    length: u64,
+    checksum: String, // md5 hex of the plaintext section
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct LoggingConfig {
-    file_path: String,
-    max_size_mb: u32,
-    backup_count: u32,
+/// The vectors of one embedding space within the embeddings section.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EmbeddingBlock {
+    space: u32,
+    quantization: Quantization,
+    #[serde(default)]
+    pq: Option<PqParams>,
+    chunks: Vec<usize>, // Positions in `ArchiveMetadata.chunks`, one vector each
+    offset: u64,        // Relative to the start of the section
+    length: u64,
 }
 
-impl MoonBallArchive {
-    pub fn new() -> Self {
-        let config = load_config().expect("Failed to load configuration");
-        MoonBallArchive {
-            metadata: ArchiveMetadata {
-                chunks: Vec::new(),
-                encryption_enabled: false,
-                requires_2fa: config.two_factor_authentication.enabled,
-                secret_key: None, // Initialize with actual secret key
-            },
-            files: Mutex::new(HashMap::new()),
-            config,
+/// Location of the HNSW graph built over one embedding block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AnnIndex {
+    block: usize, // Index into `ArchiveMetadata.embedding_blocks`
+    m: usize,
+    entry_point: usize,
+    max_level: usize,
+    offset: u64, // Relative to the start of the "ann" section
+    length: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ArchiveMetadata {
+    chunks: Vec<ChunkMetadata>,
+    encryption_enabled: bool,
+    requires_2fa: bool,
+    secret_key: Option<String>, // 2FA Secret Key
+    #[serde(default)]
+    otp_period: Option<u64>, // TOTP time step in seconds; older archives use 30
+    #[serde(default)]
+    otp_algorithm: Option<String>, // TOTP hash; older archives use SHA256
+    #[serde(default)]
+    base_archive: Option<String>, // Set for snapshot archives
+    #[serde(default)]
+    solid_blocks: Vec<ChunkMetadata>, // Stored blocks of grouped small files
+    #[serde(default)]
+    dictionaries: Vec<DictionaryMetadata>,
+    #[serde(default)]
+    scheme: Option<Scheme>, // Compression scheme the archive was written with
+    #[serde(default)]
+    embedding_spaces: Vec<EmbeddingSpace>,
+    #[serde(default)]
+    embedding_blocks: Vec<EmbeddingBlock>, // Embeddings moved out of `chunks` into the "embeddings" section
+    #[serde(default)]
+    ann_indexes: Vec<AnnIndex>,
+    #[serde(default)]
+    sections: Vec<SectionMetadata>,
+}
+
+impl ArchiveMetadata {
+    /// Returns the ID of the space `embedding` belongs to, registering a new one if needed.
+    fn embedding_space_for(&mut self, embedding: &Embedding) -> u32 {
+        if let Some(space) = self
+            .embedding_spaces
+            .iter()
+            .find(|space| space.matches(embedding))
+        {
+            return space.id;
         }
+        let id = self.embedding_spaces.len() as u32 + 1;
+        self.embedding_spaces.push(EmbeddingSpace {
+            id,
+            model: embedding.model.clone(),
+            version: embedding.version.clone(),
+            dimension: embedding.vector.len(),
+            normalized: true,
+        });
+        id
     }
 
-    pub fn add_file(&self, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let file = File::open(file_path)?;
-        let mut reader = BufReader::new(file);
-        let mut buffer = vec![0; self.config.chunk_size];
-        let mut chunk_id = 0;
+    fn embedding_space(&self, id: u32) -> Option<&EmbeddingSpace> {
+        self.embedding_spaces.iter().find(|space| space.id == id)
+    }
 
-        while let Ok(size) = reader.read(&mut buffer) {
-            if size == 0 {
-                break;
+    fn dictionary(&self, id: u32) -> Result<Vec<u8>, MoonBallError> {
+        let dictionary = self
+            .dictionaries
+            .iter()
+            .find(|dictionary| dictionary.id == id)
+            .ok_or_else(|| format!("Dictionary {} is missing from the archive", id))?;
+        Ok(base64::decode(&dictionary.data)?)
+    }
+
+    /// Byte offset of `chunk` within its original file.
+    fn file_offset(&self, chunk: &ChunkMetadata) -> u64 {
+        self.chunks
+            .iter()
+            .filter(|other| other.file_name == chunk.file_name && other.chunk_id < chunk.chunk_id)
+            .map(|other| other.original_size as u64)
+            .sum()
+    }
+
+    /// Files in the order they were added, with their total size and chunk count.
+    fn file_summaries(&self) -> Vec<FileSummary> {
+        let mut files: Vec<FileSummary> = Vec::new();
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        for chunk in &self.chunks {
+            let position = *positions
+                .entry(chunk.file_name.as_str())
+                .or_insert_with(|| {
+                    files.push(FileSummary {
+                        path: chunk.file_name.clone(),
+                        size: 0,
+                        chunks: 0,
+                        codecs: Vec::new(),
+                    });
+                    files.len() - 1
+                });
+            let file = &mut files[position];
+            file.size += chunk.original_size as u64;
+            file.chunks += 1;
+            let codec = chunk_codec(chunk);
+            if !file.codecs.iter().any(|known| known == codec) {
+                file.codecs.push(codec.to_string());
             }
-            self.compress_chunk(&buffer[..size], file_path, chunk_id)?;
-            chunk_id += 1;
         }
+        files
+    }
 
-        Ok(())
+    fn stats(&self) -> ArchiveStats {
+        let stored = self
+            .chunks
+            .iter()
+            .filter(|chunk| !chunk.base_ref && chunk.solid.is_none())
+            .chain(&self.solid_blocks);
+        let original_size: u64 = self
+            .chunks
+            .iter()
+            .map(|chunk| chunk.original_size as u64)
+            .sum();
+        let stored_size: u64 = stored.map(|chunk| chunk.compressed_size as u64).sum();
+        ArchiveStats {
+            files: self.file_summaries().len(),
+            chunks: self.chunks.len(),
+            base_chunks: self.chunks.iter().filter(|chunk| chunk.base_ref).count(),
+            original_size,
+            stored_size,
+            ratio: if stored_size == 0 {
+                0.0
+            } else {
+                original_size as f64 / stored_size as f64
+            },
+            scheme: self.scheme,
+            encrypted: self.encryption_enabled,
+            requires_2fa: self.requires_2fa,
+            base_archive: self.base_archive.clone(),
+            embedding_spaces: self.embedding_spaces.clone(),
+            sections: self
+                .sections
+                .iter()
+                .map(|section| (section.name.clone(), section.length))
+                .collect(),
+        }
     }
 
-    fn compress_chunk(&self, chunk: &[u8], file_path: &str, chunk_id: usize) -> Result<(), Box<dyn std::error::Error>> {
-        let algo = self.predict_compression_algo(chunk);
-        let compressed_data = match algo.as_str() {
-            "brotli" => brotli::CompressorReader::new(chunk, 4096, self.config.compression_algorithms.brotli.level as usize).read_to_end(&mut Vec::new())?,
-            "lzma" => lzma::LzmaWriter::new_compressor(Vec::new(), self.config.compression_algorithms.lzma.preset.as_str()).write_all(chunk)?.get_buffer().to_vec(),
-            _ => {
-                let mut output = Vec::new();
-                copy_encode(&mut &chunk[..], &mut output, self.config.compression_algorithms.zstd.level)?;
-                output
+    fn find_chunk(&self, file_name: &str, chunk_id: usize) -> Option<&ChunkMetadata> {
+        self.chunks
+            .iter()
+            .find(|chunk| chunk.file_name == file_name && chunk.chunk_id == chunk_id)
+    }
+
+    fn section(&self, name: &str) -> Option<&SectionMetadata> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// Moves the inline embeddings of every chunk with a known space into binary
+    /// blocks, one per space, and returns the encoded embeddings section.
+    fn pack_embeddings(&mut self, storage: &EmbeddingStorageConfig) -> Vec<u8> {
+        let mut section = Vec::new();
+        self.embedding_blocks.clear();
+
+        for space in &self.embedding_spaces {
+            let positions: Vec<usize> = self
+                .chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| {
+                    chunk.embedding_space == Some(space.id)
+                        && chunk
+                            .embedding
+                            .as_ref()
+                            .is_some_and(|vector| vector.len() == space.dimension)
+                })
+                .map(|(position, _)| position)
+                .collect();
+            if positions.is_empty() {
+                continue;
             }
-        };
 
-        if self.metadata.encryption_enabled {
-            let encrypted_data = self.encrypt_data(&compressed_data)?;
-            compressed_data = encrypted_data;
+            let vectors: Vec<Vec<f32>> = positions
+                .iter()
+                .filter_map(|&position| self.chunks[position].embedding.take())
+                .collect();
+            let (data, quantization, pq) =
+                QuantizedVectors::encode(&vectors, space.dimension, storage);
+            self.embedding_blocks.push(EmbeddingBlock {
+                space: space.id,
+                quantization,
+                pq,
+                chunks: positions,
+                offset: section.len() as u64,
+                length: data.len() as u64,
+            });
+            section.extend_from_slice(&data);
         }
 
-        let base64_chunk = base64::encode(chunk);
-        let output = Command::new("python3")
-            .arg("generate_embedding.py")
-            .arg(base64_chunk)
-            .output()?;
-        
-        if !output.status.success() {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to generate embedding: {}", String::from_utf8_lossy(&output.stderr)),
-            )));
-        }
+        section
+    }
+}
 
-        let output_str = String::from_utf8(output.stdout)?;
-        let embedding: Vec<f32> = serde_json::from_str(&output_str)?;
+/// Embeddings of one block, scored directly in their stored encoding.
+struct QuantizedVectors {
+    quantization: Quantization,
+    dimension: usize,
+    count: usize,
+    pq: Option<PqParams>,
+    codebook: Vec<f32>, // PQ centroids, parsed once from the front of `data`; empty otherwise
+    data: Vec<u8>,
+}
 
-        let metadata = ChunkMetadata {
-            file_name: file_path.to_string(),
-            chunk_id,
-            original_size: chunk.len(),
-            compressed_size: compressed_data.len(),
-            compression_algo: algo.clone(),
-            embedding: Some(embedding),
+impl QuantizedVectors {
+    /// Encodes `vectors` with the configured quantization and returns the one
+    /// used: product quantization falls back to int8 when the dimension can't be
+    /// split into equal subvectors. PQ parameters are set only when PQ was used.
+    fn encode(
+        vectors: &[Vec<f32>],
+        dimension: usize,
+        storage: &EmbeddingStorageConfig,
+    ) -> (Vec<u8>, Quantization, Option<PqParams>) {
+        let mut data = Vec::new();
+        let quantization = match storage.quantization {
+            Quantization::F32 => {
+                for value in vectors.iter().flatten() {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+                Quantization::F32
+            }
+            Quantization::F16 => {
+                for value in vectors.iter().flatten() {
+                    data.extend_from_slice(&half::f16::from_f32(*value).to_le_bytes());
+                }
+                Quantization::F16
+            }
+            Quantization::Pq
+                if storage.pq_subvectors > 0 && dimension.is_multiple_of(storage.pq_subvectors) =>
+            {
+                let (data, pq) = encode_pq(vectors, dimension, storage.pq_subvectors);
+                return (data, Quantization::Pq, pq);
+            }
+            Quantization::Int8 | Quantization::Pq => {
+                for vector in vectors {
+                    let max = vector
+                        .iter()
+                        .fold(0.0f32, |max, value| max.max(value.abs()));
+                    let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+                    data.extend_from_slice(&scale.to_le_bytes());
+                    data.extend(
+                        vector
+                            .iter()
+                            .map(|value| (value / scale).round().clamp(-127.0, 127.0) as i8 as u8),
+                    );
+                }
+                Quantization::Int8
+            }
         };
+        (data, quantization, None)
+    }
 
-        self.metadata.chunks.push(metadata);
+    fn from_block(
+        block: &EmbeddingBlock,
+        dimension: usize,
+        data: Vec<u8>,
+    ) -> Result<Self, MoonBallError> {
+        let count = block.chunks.len();
+        let expected = match (block.quantization, block.pq) {
+            (Quantization::F32, _) => count * dimension * 4,
+            (Quantization::F16, _) => count * dimension * 2,
+            (Quantization::Int8, _) => count * (4 + dimension),
+            (Quantization::Pq, Some(pq))
+                if pq.subvectors > 0 && dimension.is_multiple_of(pq.subvectors) =>
+            {
+                pq.centroids * dimension * 4 + count * pq.subvectors
+            }
+            (Quantization::Pq, _) => {
+                return Err(format!(
+                    "Embedding block for space {} has invalid PQ parameters",
+                    block.space
+                )
+                .into())
+            }
+        };
+        if data.len() != expected {
+            return Err(format!(
+                "Embedding block for space {} is {} bytes, expected {}",
+                block.space,
+                data.len(),
+                expected
+            )
+            .into());
+        }
+        let codebook = match (block.quantization, block.pq) {
+            (Quantization::Pq, Some(pq)) => data[..pq.centroids * dimension * 4]
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(QuantizedVectors {
+            quantization: block.quantization,
+            dimension,
+            count,
+            pq: block.pq,
+            codebook,
+            data,
+        })
+    }
 
-        // Save compressed data to memory cache
-        let mut files = self.files.lock().unwrap();
-        files.insert(format!("{}_{}.mbc", file_path, chunk_id), compressed_data);
+    /// Cosine similarity of `query` against every vector in the block.
+    fn scores(&self, query: &[f32]) -> Vec<f32> {
+        let dimension = self.dimension;
+        let query_norm = query.iter().map(|value| value * value).sum::<f32>().sqrt();
+        let cosine = |dot: f32, norm_squared: f32| {
+            if norm_squared == 0.0 || query_norm == 0.0 {
+                0.0
+            } else {
+                dot / (norm_squared.sqrt() * query_norm)
+            }
+        };
 
-        Ok(())
+        match self.quantization {
+            Quantization::F32 | Quantization::F16 => (0..self.count)
+                .map(|index| {
+                    let vector = self.decode(index);
+                    let dot = vector.iter().zip(query).map(|(a, b)| a * b).sum();
+                    cosine(dot, vector.iter().map(|value| value * value).sum())
+                })
+                .collect(),
+            Quantization::Int8 => self
+                .data
+                .chunks_exact(4 + dimension)
+                .map(|record| {
+                    // The per-vector scale cancels out of the cosine
+                    let components = record[4..].iter().map(|&byte| byte as i8 as f32);
+                    let (dot, norm_squared) = components
+                        .zip(query)
+                        .fold((0.0, 0.0), |(dot, norm), (q, x)| {
+                            (dot + q * x, norm + q * q)
+                        });
+                    cosine(dot, norm_squared)
+                })
+                .collect(),
+            Quantization::Pq => {
+                // Asymmetric distance: the query stays exact, stored vectors are
+                // scored through per-subvector lookup tables
+                let pq = self.pq.expect("validated in from_block");
+                let width = dimension / pq.subvectors;
+                let codebook = &self.codebook;
+                let mut dots = vec![0.0f32; pq.subvectors * pq.centroids];
+                let mut norms = vec![0.0f32; pq.subvectors * pq.centroids];
+                for subvector in 0..pq.subvectors {
+                    let query_part = &query[subvector * width..(subvector + 1) * width];
+                    for centroid in 0..pq.centroids {
+                        let start = (subvector * pq.centroids + centroid) * width;
+                        let center = &codebook[start..start + width];
+                        dots[subvector * pq.centroids + centroid] =
+                            center.iter().zip(query_part).map(|(a, b)| a * b).sum();
+                        norms[subvector * pq.centroids + centroid] =
+                            center.iter().map(|value| value * value).sum();
+                    }
+                }
+                self.data[pq.centroids * dimension * 4..]
+                    .chunks_exact(pq.subvectors)
+                    .map(|codes| {
+                        let (dot, norm_squared) = codes.iter().enumerate().fold(
+                            (0.0, 0.0),
+                            |(dot, norm), (subvector, &code)| {
+                                let entry = subvector * pq.centroids + code as usize;
+                                (dot + dots[entry], norm + norms[entry])
+                            },
+                        );
+                        cosine(dot, norm_squared)
+                    })
+                    .collect()
+            }
+        }
     }
 
-    fn predict_compression_algo(&self, _chunk: &[u8]) -> String {
-        // Placeholder ML logic
-        "zstd".to_string()
+    /// Reconstructs the (approximate) f32 vector at `index`.
+    fn decode(&self, index: usize) -> Vec<f32> {
+        let dimension = self.dimension;
+        match self.quantization {
+            Quantization::F32 => self.data[index * dimension * 4..(index + 1) * dimension * 4]
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect(),
+            Quantization::F16 => self.data[index * dimension * 2..(index + 1) * dimension * 2]
+                .chunks_exact(2)
+                .map(|bytes| half::f16::from_le_bytes([bytes[0], bytes[1]]).to_f32())
+                .collect(),
+            Quantization::Int8 => {
+                let record = &self.data[index * (4 + dimension)..(index + 1) * (4 + dimension)];
+                let scale = f32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+                record[4..]
+                    .iter()
+                    .map(|&byte| byte as i8 as f32 * scale)
+                    .collect()
+            }
+            Quantization::Pq => {
+                let pq = self.pq.expect("validated in from_block");
+                let width = dimension / pq.subvectors;
+                let codebook = &self.codebook;
+                let codes_start = pq.centroids * dimension * 4 + index * pq.subvectors;
+                let mut vector = Vec::with_capacity(dimension);
+                for (subvector, &code) in self.data[codes_start..codes_start + pq.subvectors]
+                    .iter()
+                    .enumerate()
+                {
+                    let start = (subvector * pq.centroids + code as usize) * width;
+                    vector.extend_from_slice(&codebook[start..start + width]);
+                }
+                vector
+            }
+        }
     }
+}
 
-    pub fn save_archive(&self, archive_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let archive_file = File::create(archive_path)?;
-        let mut writer = BufWriter::new(archive_file);
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
 
-        let metadata_json = serde_json::to_string(&self.metadata)?;
+/// BM25 inverted index over the text chunks of an archive, stored zstd-compressed
+/// in the "keywords" section.
+#[derive(Serialize, Deserialize, Default)]
+struct KeywordIndex {
+    documents: Vec<usize>, // Chunk positions; postings refer to entries here
+    lengths: Vec<u32>,     // Token count of each document
+    postings: HashMap<String, Vec<(u32, u32)>>, // Term to (document, term frequency)
+}
 
-        if self.metadata.encryption_enabled {
-            let encrypted_metadata = self.encrypt_data(metadata_json.as_bytes())?;
-            writer.write_all(&encrypted_metadata)?;
-        } else {
-            writer.write_all(metadata_json.as_bytes())?;
+impl KeywordIndex {
+    fn add(&mut self, position: usize, data: &[u8]) {
+        let text = String::from_utf8_lossy(data);
+        let mut frequencies: HashMap<String, u32> = HashMap::new();
+        for token in tokenize(&text) {
+            *frequencies.entry(token).or_default() += 1;
+        }
+        if frequencies.is_empty() {
+            return;
         }
 
-        writer.write_all(b"\n")?;
+        let document = self.documents.len() as u32;
+        self.documents.push(position);
+        self.lengths.push(frequencies.values().sum());
+        for (term, frequency) in frequencies {
+            self.postings
+                .entry(term)
+                .or_default()
+                .push((document, frequency));
+        }
+    }
 
-        for (file_name, data) in self.files.lock().unwrap().iter() {
-            writer.write_all(data)?;
-            writer.write_all(b"\n")?;
+    /// Drops the documents from `count` on.
+    fn truncate(&mut self, count: usize) {
+        if self.documents.len() <= count {
+            return;
+        }
+        self.documents.truncate(count);
+        self.lengths.truncate(count);
+        for postings in self.postings.values_mut() {
+            postings.retain(|&(document, _)| (document as usize) < count);
         }
+        self.postings.retain(|_, postings| !postings.is_empty());
+    }
 
-        let checksum = format!("{:x}", md5::compute(metadata_json));
-        if self.metadata.encryption_enabled {
-            let encrypted_checksum = self.encrypt_data(checksum.as_bytes())?;
-            writer.write_all(&encrypted_checksum)?;
-        } else {
-            writer.write_all(checksum.as_bytes())?;
+    /// Returns up to `limit` chunk positions with their BM25 scores, best first.
+    fn search(&self, query: &str, limit: usize) -> Vec<(usize, f32)> {
+        if self.documents.is_empty() {
+            return Vec::new();
         }
+        let count = self.documents.len() as f32;
+        let average_length = self
+            .lengths
+            .iter()
+            .map(|&length| length as f32)
+            .sum::<f32>()
+            / count;
 
-        Ok(())
-    }
+        let mut terms: Vec<String> = tokenize(query).collect();
+        terms.sort();
+        terms.dedup();
 
-    pub fn extract(&self, archive_path: &str, output_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
-        if self.metadata.requires_2fa {
-            let secret_key = self.metadata.secret_key.clone().ok_or("2FA secret key missing")?;
-            let current_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-            let expected_otp = totp_custom::<Sha256>(&secret_key, current_time, 30, 6, &RFC4648 { padding: false });
-            if let Some(provided_otp) = self.ask_for_otp() {
-                if provided_otp != expected_otp {
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::PermissionDenied,
-                        "Invalid OTP",
-                    )));
-                }
-            } else {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::PermissionDenied,
-                    "OTP required for extraction",
-                )));
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let frequency_in_corpus = postings.len() as f32;
+            let idf =
+                (1.0 + (count - frequency_in_corpus + 0.5) / (frequency_in_corpus + 0.5)).ln();
+            for &(document, frequency) in postings {
+                let frequency = frequency as f32;
+                let length = self.lengths[document as usize] as f32;
+                let saturation =
+                    frequency + BM25_K1 * (1.0 - BM25_B + BM25_B * length / average_length);
+                *scores.entry(document).or_default() +=
+                    idf * frequency * (BM25_K1 + 1.0) / saturation;
             }
         }
 
-        let archive_file = File::open(archive_path)?;
-        let mut reader = BufReader::new(archive_file);
-        let mut metadata_str = String::new();
-        reader.read_line(&mut metadata_str)?;
+        let mut ranked: Vec<(usize, f32)> = scores
+            .into_iter()
+            .map(|(document, score)| (self.documents[document as usize], score))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked.truncate(limit);
+        ranked
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, MoonBallError> {
+        Ok(zstd::encode_all(serde_json::to_vec(self)?.as_slice(), 3)?)
+    }
 
-        if self.metadata.encryption_enabled {
-            let decrypted_metadata = self.decrypt_data(metadata_str.as_bytes())?;
-            let metadata: ArchiveMetadata = serde_json::from_slice(&decrypted_metadata)?;
-        } else {
-            let metadata: ArchiveMetadata = serde_json::from_str(&metadata_str)?;
+    fn from_bytes(data: &[u8]) -> Result<Self, MoonBallError> {
+        let index: KeywordIndex = serde_json::from_slice(&zstd::decode_all(data)?)?;
+        if index.documents.len() != index.lengths.len()
+            || index
+                .postings
+                .values()
+                .flatten()
+                .any(|&(document, _)| document as usize >= index.documents.len())
+        {
+            return Err("Keyword index is corrupt".into());
+        }
+        Ok(index)
+    }
+}
+
+/// Merges rankings, best first, with reciprocal rank fusion: an item scores
+/// `1 / (k + rank)` in each ranking it appears in, counting ranks from 1.
+fn reciprocal_rank_fusion<T: Eq + std::hash::Hash>(rankings: Vec<Vec<T>>, k: f32) -> Vec<(T, f32)> {
+    let mut fused: HashMap<T, f32> = HashMap::new();
+    for ranking in rankings {
+        for (rank, item) in ranking.into_iter().enumerate() {
+            *fused.entry(item).or_default() += 1.0 / (k + rank as f32 + 1.0);
         }
+    }
+    let mut fused: Vec<(T, f32)> = fused.into_iter().collect();
+    fused.sort_by(|a, b| b.1.total_cmp(&a.1));
+    fused
+}
 
-        for (index, chunk_metadata) in metadata.chunks.iter().enumerate() {
-            let mut compressed_data = vec![0; chunk_metadata.compressed_size];
-            reader.read_exact(&mut compressed_data)?;
+/// Splits text into lowercase identifier-like tokens, so names such as
+/// `snake_case` or error codes such as `E0425` stay whole.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|token| !token.is_empty() && token.len() <= 64)
+        .map(str::to_lowercase)
+}
 
-            if self.metadata.encryption_enabled {
-                let decrypted_chunk = self.decrypt_data(&compressed_data)?;
-                compressed_data = decrypted_chunk;
-            }
+/// A single-line excerpt of `data` around the first query term it contains, or
+/// its beginning when none matches, with every occurrence of a query term
+/// highlighted. Binary chunks get no snippet.
+fn snippet(data: &[u8], query: &str, length: usize) -> Option<Snippet> {
+    if data.contains(&0) {
+        return None;
+    }
+    let text = String::from_utf8_lossy(data);
+    // Lowercased the same way as the text, which differs from `str::to_lowercase`
+    // for a word-final capital sigma
+    let terms: Vec<String> = tokenize(&lowercase_with_spans(query).0).collect();
+    let (lowered, spans) = lowercase_with_spans(&text);
+    let found = terms
+        .iter()
+        .filter_map(|term| lowered.find(term.as_str()))
+        .min()
+        .map_or(0, |position| spans[position].start);
 
-            let output_path = std::path::Path::new(output_dir).join(&chunk_metadata.file_name);
-            let mut output_file = File::create(&output_path)?;
+    let mut start = found.saturating_sub(length / 4);
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let excerpt: String = text[start..].chars().take(length).collect();
+    let text = excerpt.split_whitespace().collect::<Vec<_>>().join(" ");
 
-            match chunk_metadata.compression_algo.as_str() {
-                "brotli" => {
-                    let mut decompressor = brotli::Decompressor::new(&compressed_data[..], 4096);
-                    std::io::copy(&mut decompressor, &mut output_file)?;
-                }
-                "lzma" => {
-                    let mut decompressor = lzma::LzmaReader::new_decompressor(&compressed_data[..])?;
-                    std::io::copy(&mut decompressor, &mut output_file)?;
-                }
-                _ => {
-                    copy_decode(&mut &compressed_data[..], &mut output_file)?;
-                }
-            }
+    let (lowered, spans) = lowercase_with_spans(&text);
+    let mut highlights: Vec<std::ops::Range<usize>> = terms
+        .iter()
+        .flat_map(|term| {
+            lowered
+                .match_indices(term.as_str())
+                .map(|(position, matched)| {
+                    spans[position].start..spans[position + matched.len() - 1].end
+                })
+        })
+        .collect();
+    highlights.sort_by_key(|range| range.start);
+    let mut merged: Vec<std::ops::Range<usize>> = Vec::new();
+    for range in highlights {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
         }
+    }
 
-        Ok(())
+    Some(Snippet {
+        text,
+        highlights: merged,
+    })
+}
+
+/// Lowercases `text` one character at a time. Lowercasing can change a character's
+/// length, so each byte of the result also records the byte range of the
+/// character in `text` it came from.
+fn lowercase_with_spans(text: &str) -> (String, Vec<std::ops::Range<usize>>) {
+    let mut lowered = String::with_capacity(text.len());
+    let mut spans = Vec::with_capacity(text.len());
+    for (position, c) in text.char_indices() {
+        lowered.extend(c.to_lowercase());
+        spans.resize(lowered.len(), position..position + c.len_utf8());
     }
+    (lowered, spans)
+}
 
-    fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let salt = rand::thread_rng().gen::<[u8; 16]>();
+const HNSW_MAX_LEVEL: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    score: f32,
+    node: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+/// Picks up to `limit` neighbors from `candidates` (best first, scored against the
+/// node being linked), skipping any candidate closer to an already picked neighbor
+/// than to the node. Keeping only the nearest would link clustered embeddings
+/// among themselves and leave other clusters unreachable.
+fn select_neighbors(vectors: &[Vec<f32>], candidates: &[Candidate], limit: usize) -> Vec<u32> {
+    let mut selected: Vec<u32> = Vec::with_capacity(limit);
+    for candidate in candidates {
+        if selected.len() == limit {
+            break;
+        }
+        let vector = &vectors[candidate.node];
+        if selected
+            .iter()
+            .all(|&other| dot_product(vector, &vectors[other as usize]) < candidate.score)
+        {
+            selected.push(candidate.node as u32);
+        }
+    }
+    selected
+}
+
+/// Hierarchical navigable small world graph over the vectors of one embedding
+/// block. Nodes are positions within the block; similarity is the inner product.
+struct Hnsw {
+    m: usize,
+    entry_point: usize,
+    max_level: usize,
+    neighbors: Vec<Vec<Vec<u32>>>, // [node][level]
+}
+
+impl Hnsw {
+    fn build(vectors: &[Vec<f32>], config: &AnnConfig) -> Self {
+        let m = config.m.max(2);
+        let level_factor = 1.0 / (m as f64).ln();
+        let mut graph = Hnsw {
+            m,
+            entry_point: 0,
+            max_level: 0,
+            neighbors: Vec::with_capacity(vectors.len()),
+        };
+
+        for node in 0..vectors.len() {
+            // Levels come from a hash of the node so rebuilding gives the same graph
+            let uniform = (splitmix64(node as u64) >> 11) as f64 / (1u64 << 53) as f64;
+            let level = ((-uniform.max(f64::MIN_POSITIVE).ln() * level_factor) as usize)
+                .min(HNSW_MAX_LEVEL);
+            graph.neighbors.push(vec![Vec::new(); level + 1]);
+            if node == 0 {
+                graph.max_level = level;
+                continue;
+            }
+
+            let similarity = |other: usize| dot_product(&vectors[other], &vectors[node]);
+            let mut entry = graph.entry_point;
+            for layer in (level + 1..=graph.max_level).rev() {
+                entry = graph.search_layer(&similarity, entry, 1, layer)[0].node;
+            }
+            for layer in (0..=level.min(graph.max_level)).rev() {
+                let found =
+                    graph.search_layer(&similarity, entry, config.ef_construction.max(m), layer);
+                let limit = graph.max_neighbors(layer);
+                let selected = select_neighbors(vectors, &found, limit);
+                for &neighbor in &selected {
+                    let base = &vectors[neighbor as usize];
+                    let list = &mut graph.neighbors[neighbor as usize][layer];
+                    list.push(node as u32);
+                    if list.len() > limit {
+                        let mut candidates: Vec<Candidate> = list
+                            .iter()
+                            .map(|&other| Candidate {
+                                score: dot_product(base, &vectors[other as usize]),
+                                node: other as usize,
+                            })
+                            .collect();
+                        candidates.sort_by(|a, b| b.cmp(a));
+                        *list = select_neighbors(vectors, &candidates, limit);
+                    }
+                }
+                graph.neighbors[node][layer] = selected;
+                entry = found[0].node;
+            }
+            if level > graph.max_level {
+                graph.max_level = level;
+                graph.entry_point = node;
+            }
+        }
+
+        graph
+    }
+
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.m * 2
+        } else {
+            self.m
+        }
+    }
+
+    /// Returns up to `ef` nodes closest to the query, best first.
+    fn search(&self, similarity: &dyn Fn(usize) -> f32, ef: usize) -> Vec<Candidate> {
+        let mut entry = self.entry_point;
+        for layer in (1..=self.max_level).rev() {
+            entry = self.search_layer(similarity, entry, 1, layer)[0].node;
+        }
+        self.search_layer(similarity, entry, ef, 0)
+    }
+
+    fn search_layer(
+        &self,
+        similarity: &dyn Fn(usize) -> f32,
+        entry: usize,
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let start = Candidate {
+            score: similarity(entry),
+            node: entry,
+        };
+        let mut visited = HashSet::from([entry]);
+        let mut candidates = BinaryHeap::from([start]);
+        let mut results = BinaryHeap::from([Reverse(start)]);
+
+        while let Some(current) = candidates.pop() {
+            let worst = results
+                .peek()
+                .map_or(f32::MIN, |Reverse(worst)| worst.score);
+            if current.score < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbor in self.neighbors[current.node]
+                .get(layer)
+                .map_or(&[][..], Vec::as_slice)
+            {
+                let neighbor = neighbor as usize;
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let candidate = Candidate {
+                    score: similarity(neighbor),
+                    node: neighbor,
+                };
+                if results.len() < ef
+                    || results
+                        .peek()
+                        .is_none_or(|Reverse(worst)| candidate.score > worst.score)
+                {
+                    candidates.push(candidate);
+                    results.push(Reverse(candidate));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut found: Vec<Candidate> = results
+            .into_iter()
+            .map(|Reverse(candidate)| candidate)
+            .collect();
+        found.sort_by(|a, b| b.cmp(a));
+        found
+    }
+
+    /// Per node: its level as one byte, then for each level a u16 count and the neighbor IDs as u32.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for levels in &self.neighbors {
+            data.push((levels.len() - 1) as u8);
+            for neighbors in levels {
+                data.extend_from_slice(&(neighbors.len() as u16).to_le_bytes());
+                for neighbor in neighbors {
+                    data.extend_from_slice(&neighbor.to_le_bytes());
+                }
+            }
+        }
+        data
+    }
+
+    fn from_bytes(index: &AnnIndex, count: usize, data: &[u8]) -> Result<Self, MoonBallError> {
+        let corrupt = || format!("ANN index for embedding block {} is corrupt", index.block);
+        let mut cursor = 0;
+        let mut take = |length: usize| -> Result<&[u8], String> {
+            let bytes = data.get(cursor..cursor + length).ok_or_else(corrupt)?;
+            cursor += length;
+            Ok(bytes)
+        };
+
+        let mut neighbors = Vec::with_capacity(count);
+        for _ in 0..count {
+            let level = take(1)?[0] as usize;
+            let mut levels = Vec::with_capacity(level + 1);
+            for _ in 0..=level {
+                let length = u16::from_le_bytes(take(2)?.try_into()?) as usize;
+                let list = take(length * 4)?
+                    .chunks_exact(4)
+                    .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                    .collect::<Vec<u32>>();
+                if list.iter().any(|&neighbor| neighbor as usize >= count) {
+                    return Err(corrupt().into());
+                }
+                levels.push(list);
+            }
+            neighbors.push(levels);
+        }
+        if index.entry_point >= count || neighbors[index.entry_point].len() != index.max_level + 1 {
+            return Err(corrupt().into());
+        }
+
+        Ok(Hnsw {
+            m: index.m,
+            entry_point: index.entry_point,
+            max_level: index.max_level,
+            neighbors,
+        })
+    }
+}
+
+const PQ_TRAINING_SAMPLES: usize = 16 * 1024;
+const PQ_ITERATIONS: usize = 8;
+
+/// Trains one k-means codebook per subvector and encodes every vector as one
+/// centroid index per subvector. The codebook is stored ahead of the codes.
+fn encode_pq(
+    vectors: &[Vec<f32>],
+    dimension: usize,
+    subvectors: usize,
+) -> (Vec<u8>, Option<PqParams>) {
+    let width = dimension / subvectors;
+    let centroids = vectors.len().clamp(1, 256);
+    let stride = (vectors.len() / PQ_TRAINING_SAMPLES).max(1);
+    let samples: Vec<&Vec<f32>> = vectors.iter().step_by(stride).collect();
+    let nearest = |center_set: &[f32], part: &[f32]| -> usize {
+        center_set
+            .chunks_exact(width)
+            .map(|center| {
+                center
+                    .iter()
+                    .zip(part)
+                    .map(|(a, b)| (a - b) * (a - b))
+                    .sum::<f32>()
+            })
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(0, |(index, _)| index)
+    };
+
+    let codebooks: Vec<Vec<f32>> = (0..subvectors)
+        .into_par_iter()
+        .map(|subvector| {
+            let part =
+                |vector: &Vec<f32>| vector[subvector * width..(subvector + 1) * width].to_vec();
+            // Deterministic initialisation from evenly spaced samples
+            let mut centers: Vec<f32> = (0..centroids)
+                .flat_map(|index| part(samples[index * samples.len() / centroids]))
+                .collect();
+            for _ in 0..PQ_ITERATIONS {
+                let mut sums = vec![0.0f32; centroids * width];
+                let mut counts = vec![0usize; centroids];
+                for sample in &samples {
+                    let values = part(sample);
+                    let center = nearest(&centers, &values);
+                    counts[center] += 1;
+                    for (sum, value) in sums[center * width..(center + 1) * width]
+                        .iter_mut()
+                        .zip(&values)
+                    {
+                        *sum += value;
+                    }
+                }
+                for (center, &count) in counts.iter().enumerate().filter(|&(_, &count)| count > 0) {
+                    for dimension_index in 0..width {
+                        centers[center * width + dimension_index] =
+                            sums[center * width + dimension_index] / count as f32;
+                    }
+                }
+            }
+            centers
+        })
+        .collect();
+
+    // Codebook layout: centroid-major within each subvector
+    let mut data = Vec::with_capacity(centroids * dimension * 4 + vectors.len() * subvectors);
+    for codebook in &codebooks {
+        for value in codebook {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    for vector in vectors {
+        for (subvector, codebook) in codebooks.iter().enumerate() {
+            data.push(nearest(
+                codebook,
+                &vector[subvector * width..(subvector + 1) * width],
+            ) as u8);
+        }
+    }
+    (
+        data,
+        Some(PqParams {
+            subvectors,
+            centroids,
+        }),
+    )
+}
+
+/// Progress of a long-running operation, passed to a `ProgressObserver`.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    pub operation: String, // "Compressing", "Writing" or "Extracting"
+    pub current_file: String,
+    pub bytes_done: u64,
+    pub bytes_total: u64, // Zero when unknown
+    pub chunks_done: usize,
+    pub chunks_total: usize, // Zero when unknown, as while files are still being chunked
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Time left, extrapolated from the byte rate so far.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.bytes_done == 0 || self.bytes_total < self.bytes_done {
+            return None;
+        }
+        let remaining = (self.bytes_total - self.bytes_done) as f64 / self.bytes_done as f64;
+        Some(self.elapsed.mul_f64(remaining))
+    }
+}
+
+/// Receives updates from `add_paths`/`add_file`, `save_archive` and extraction.
+/// Called on the thread doing the work, after every chunk.
+pub trait ProgressObserver: Send + Sync {
+    fn on_progress(&self, progress: &Progress);
+
+    /// Called once when an operation completes.
+    fn on_finish(&self, _progress: &Progress) {}
+}
+
+/// Stops an operation at the next chunk boundary once cancelled. Clones share
+/// the same flag, so one can be handed to another thread or a signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+struct ProgressTracker {
+    progress: Progress,
+    started: Instant,
+}
+
+impl ProgressTracker {
+    fn new(operation: &str, bytes_total: u64, chunks_total: usize) -> Self {
+        ProgressTracker {
+            progress: Progress {
+                operation: operation.to_string(),
+                bytes_total,
+                chunks_total,
+                ..Progress::default()
+            },
+            started: Instant::now(),
+        }
+    }
+}
+
+pub struct MoonBallArchive {
+    metadata: ArchiveMetadata,
+    files: Mutex<HashMap<String, Vec<u8>>>, // Cache to store compressed chunks in memory for parallel operations
+    base_chunks: HashMap<String, ChunkMetadata>, // Chunks already stored in the snapshot base, keyed by hash
+    solid_enabled: bool,
+    solid_block: Vec<u8>,               // Pending solid block contents
+    solid_members: Vec<usize>, // Indexes into `metadata.chunks` waiting for the pending block
+    dictionary: Option<(u32, Vec<u8>)>, // Active zstd dictionary for new chunks
+    settings: SchemeSettings,
+    codecs: CodecRegistry,
+    extra_codecs: Vec<String>, // Third-party codecs offered to the selector, preferred first
+    keyword_index: KeywordIndex, // Built over text chunks as they are added
+    embeddings_enabled: bool,
+    embedding_failed: bool, // An embedding has failed and been reported already
+    otp: Option<String>,    // One-time password supplied up front by non-interactive frontends
+    observer: Option<Arc<dyn ProgressObserver>>,
+    cancellation: CancellationToken,
+    progress: Mutex<ProgressTracker>, // State of the operation currently reported to `observer`
+    report: Mutex<RunReport>,         // Outcome of the last `add_paths` or extraction
+    config: Config,
+}
+
+/// Archive state before a file is added, restored if adding it fails.
+struct AddCheckpoint {
+    chunks: usize,
+    solid_members: usize,
+    solid_block: usize,
+    keyword_documents: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Fast,
+    Balanced,
+    Max,
+}
+
+impl Scheme {
+    /// The scheme's bits within the header flags; 0 means not recorded.
+    fn header_bits(self) -> u8 {
+        let code = match self {
+            Scheme::Fast => 1,
+            Scheme::Balanced => 2,
+            Scheme::Max => 3,
+        };
+        code << FLAG_SCHEME_MASK.trailing_zeros()
+    }
+
+    fn from_header_flags(flags: u8) -> Option<Scheme> {
+        match (flags & FLAG_SCHEME_MASK) >> FLAG_SCHEME_MASK.trailing_zeros() {
+            1 => Some(Scheme::Fast),
+            2 => Some(Scheme::Balanced),
+            3 => Some(Scheme::Max),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "fast" => Ok(Scheme::Fast),
+            "balanced" => Ok(Scheme::Balanced),
+            "max" => Ok(Scheme::Max),
+            other => Err(format!(
+                "Unknown compression scheme '{}' (expected fast, balanced or max)",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ChunkingStrategy {
+    Fixed(usize),
+    // Gear-hash content-defined chunking, so insertions only disturb nearby chunks
+    ContentDefined {
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    },
+}
+
+impl ChunkingStrategy {
+    fn read_size(&self) -> usize {
+        match *self {
+            ChunkingStrategy::Fixed(size) => size,
+            ChunkingStrategy::ContentDefined { max_size, .. } => max_size,
+        }
+    }
+
+    /// Returns where the next chunk of `data` ends, or `None` if more input is needed.
+    fn cut_point(&self, data: &[u8], eof: bool) -> Option<usize> {
+        if data.is_empty() {
+            return None;
+        }
+
+        match *self {
+            ChunkingStrategy::Fixed(size) => {
+                if data.len() >= size {
+                    Some(size)
+                } else if eof {
+                    Some(data.len())
+                } else {
+                    None
+                }
+            }
+            ChunkingStrategy::ContentDefined {
+                min_size,
+                avg_size,
+                max_size,
+            } => {
+                let bits = (avg_size.max(2) as f64).log2().round() as u32;
+                let gear = gear_table();
+                let mut hash = 0u64;
+                for (index, byte) in data.iter().enumerate().take(max_size).skip(min_size) {
+                    hash = (hash << 1).wrapping_add(gear[*byte as usize]);
+                    if hash >> (64 - bits) == 0 {
+                        return Some(index + 1);
+                    }
+                }
+
+                if data.len() >= max_size {
+                    Some(max_size)
+                } else if eof {
+                    Some(data.len())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Concrete settings a compression scheme resolves to.
+#[derive(Debug, Clone)]
+struct SchemeSettings {
+    algorithms: Vec<String>, // Candidate codec names for `predict_compression_algo`, preferred first
+    try_all: bool,           // Compress with every candidate and keep the smallest
+    zstd_level: i32,
+    brotli_level: u32,
+    lzma_preset: u32,
+    window_log: u32, // log2 of the zstd and brotli window size
+    chunking: ChunkingStrategy,
+}
+
+impl SchemeSettings {
+    /// `compression_level` (1-9) picks a level inside each scheme's range; the
+    /// per-algorithm config levels act as an upper bound.
+    fn resolve(scheme: Scheme, config: &Config) -> Self {
+        let position = (config.compression_level.clamp(1, 9) - 1) as f32 / 8.0;
+        let pick = |low: i32, high: i32| low + ((high - low) as f32 * position).round() as i32;
+        let algorithms = &config.compression_algorithms;
+        let chunk_size = config.chunk_size;
+
+        let (candidates, try_all, zstd, brotli, lzma, window_log, chunking) = match scheme {
+            Scheme::Fast => (
+                vec!["lz4", "zstd"],
+                false,
+                pick(1, 3),
+                pick(1, 4),
+                pick(0, 2),
+                20,
+                ChunkingStrategy::Fixed(chunk_size * 2),
+            ),
+            Scheme::Balanced => (
+                vec!["zstd", "brotli"],
+                false,
+                pick(3, 12),
+                pick(4, 8),
+                pick(3, 6),
+                23,
+                ChunkingStrategy::Fixed(chunk_size),
+            ),
+            Scheme::Max => (
+                vec!["zstd", "xz", "lzma", "brotli", "zstd-long"],
+                true,
+                pick(12, 19),
+                pick(9, 11),
+                pick(7, 9),
+                27,
+                ChunkingStrategy::ContentDefined {
+                    min_size: chunk_size / 4,
+                    avg_size: chunk_size,
+                    max_size: chunk_size * 2,
+                },
+            ),
+        };
+
+        SchemeSettings {
+            algorithms: candidates.into_iter().map(String::from).collect(),
+            try_all,
+            zstd_level: zstd.min(algorithms.zstd.level),
+            brotli_level: (brotli as u32).min(algorithms.brotli.level),
+            lzma_preset: (lzma as u32).min(algorithms.lzma.preset.parse().unwrap_or(9)),
+            window_log,
+            chunking,
+        }
+    }
+
+    /// Chunking for a file of `file_size` bytes. Long-range mode only pays off on
+    /// chunks spanning its window, so when the scheme offers it, files of at least
+    /// `min_chunk_size` are cut into window-sized chunks instead.
+    fn chunking_for(&self, file_size: u64, zstd_long: &ZstdLongConfig) -> ChunkingStrategy {
+        if self.algorithms.iter().any(|algo| algo == "zstd-long")
+            && file_size >= zstd_long.min_chunk_size as u64
+        {
+            let window = 1u64 << zstd_long.window_log;
+            return ChunkingStrategy::Fixed(window.min(file_size) as usize);
+        }
+        self.chunking
+    }
+
+    /// The level a codec runs at under this scheme. Codecs outside the built-in
+    /// families get the raw `compression_level`.
+    fn level_for(&self, codec: &str, config: &Config) -> i32 {
+        match codec {
+            "zstd" => self.zstd_level,
+            "zstd-long" => self
+                .zstd_level
+                .min(config.compression_algorithms.zstd_long.level),
+            "brotli" => self.brotli_level as i32,
+            "lzma" => self.lzma_preset as i32,
+            "xz" => self
+                .lzma_preset
+                .min(config.compression_algorithms.xz.preset) as i32,
+            "lz4" => config.compression_algorithms.lz4.level as i32,
+            _ => config.compression_level as i32,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Config {
+    preset: String,
+    custom_models: HashMap<String, String>,
+    multi_modal_model: String,
+    fallback_model: String,
+    fallback_provider_settings: FallbackProviderSettings,
+    chat_provider: String, // Which of `fallback_provider_settings` answers --ask
+    caching: bool,
+    model_download_path: String,
+    logging_level: String,
+    #[serde(deserialize_with = "string_or_number")]
+    parallel_threads: String,
+    compression_level: u8,
+    chunk_size: usize,
+    error_handling: String,
+    #[serde(default)]
+    retry: RetryConfig, // Backoff for error_handling: retry
+    auto_update_models: bool,
+    scheme: String,
+    preset_configs: HashMap<String, PresetConfig>,
+    semantic_search: SemanticSearchConfig,
+    encryption: EncryptionConfig,
+    two_factor_authentication: TwoFactorAuthenticationConfig,
+    compression_algorithms: CompressionAlgorithmsConfig,
+    logging: LoggingConfig,
+    #[serde(default)]
+    solid: SolidConfig,
+    #[serde(default)]
+    dictionary: DictionaryConfig,
+}
+
+const PRESETS: &[&str] = &["speed", "accuracy", "default", "custom", "fallback"];
+const MODEL_CATEGORIES: &[&str] = &["Text", "Image", "Audio", "Video", "Structured_Data", "Code"];
+const LOGGING_LEVELS: &[&str] = &["DEBUG", "INFO", "WARNING", "ERROR"];
+const OTP_ALGORITHMS: &[&str] = &["SHA1", "SHA256", "SHA512"];
+
+/// Model names generate_embedding.py knows, mirroring its `MODEL_IDS` table.
+const EMBEDDER_MODELS: &[&str] = &[
+    "distilbert-base-uncased",
+    "bert-base-uncased",
+    "codebert",
+    "gpt-neo",
+    "CLIP",
+    "wav2vec2-base",
+];
+
+/// The script also accepts Hugging Face repository IDs (`owner/name`), which it
+/// loads as text models.
+fn embedder_supports(model: &str) -> bool {
+    EMBEDDER_MODELS.contains(&model) || model.contains('/')
+}
+const CHAT_PROVIDERS: &[&str] = &["ollama", "openai"];
+
+fn models(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(category, model)| (category.to_string(), model.to_string()))
+        .collect()
+}
+
+const SYSTEM_MESSAGE: &str = "You are an AI assistant tasked with governing this .mnbl/.🌕 file. You will answer user queries utilizing the integrated RAG system of embedding and the file index in the header of the .mnbl/.🌕 file. You will tell the user relevant information pertaining to their queries to the best of your ability leveraging available knowledge of the contents of the .mnbl/.🌕 file and your own knowledge-base in general as well as any available tools.";
+
+impl Default for Config {
+    fn default() -> Self {
+        let default_models = models(&[
+            ("Text", "distilbert-base-uncased"),
+            ("Image", "CLIP"),
+            ("Audio", "wav2vec2-base"),
+            ("Structured_Data", "distilbert-base-uncased"),
+            ("Code", "codebert"),
+        ]);
+        let mut preset_configs = HashMap::new();
+        preset_configs.insert(
+            "speed".to_string(),
+            PresetConfig {
+                custom_models: models(&[
+                    ("Text", "distilbert-base-uncased"),
+                    ("Image", "CLIP"),
+                    ("Audio", "wav2vec2-base"),
+                    ("Structured_Data", "distilbert-base-uncased"),
+                    ("Code", "distilbert-base-uncased"),
+                ]),
+                multi_modal: false,
+            },
+        );
+        preset_configs.insert(
+            "accuracy".to_string(),
+            PresetConfig {
+                custom_models: models(&[
+                    ("Text", "bert-base-uncased"),
+                    ("Image", "CLIP"),
+                    ("Audio", "wav2vec2-base"),
+                    ("Structured_Data", "bert-base-uncased"),
+                    ("Code", "codebert"),
+                ]),
+                multi_modal: true,
+            },
+        );
+        preset_configs.insert(
+            "default".to_string(),
+            PresetConfig {
+                custom_models: default_models.clone(),
+                multi_modal: true,
+            },
+        );
+
+        Config {
+            preset: "default".to_string(),
+            custom_models: default_models,
+            multi_modal_model: "CLIP".to_string(),
+            fallback_model: "distilbert-base-uncased".to_string(),
+            fallback_provider_settings: FallbackProviderSettings {
+                ollama: OllamaSettings {
+                    provider: "ollama".to_string(),
+                    model: "AUTODETECT".to_string(),
+                    context_length: 32768,
+                    api_base: "http://localhost:11434".to_string(),
+                    system_message: SYSTEM_MESSAGE.to_string(),
+                },
+                openai: OpenAISettings {
+                    provider: "openai".to_string(),
+                    model: "AUTODETECT".to_string(),
+                    context_length: 32768,
+                    api_key: String::new(),
+                    api_base: "http://localhost:5000/v1".to_string(),
+                    system_message: SYSTEM_MESSAGE.to_string(),
+                },
+            },
+            chat_provider: "ollama".to_string(),
+            caching: true,
+            model_download_path: "./models".to_string(),
+            logging_level: "INFO".to_string(),
+            parallel_threads: "auto".to_string(),
+            compression_level: 9,
+            chunk_size: 5 * 1024 * 1024,
+            error_handling: "continue".to_string(),
+            retry: RetryConfig::default(),
+            auto_update_models: true,
+            scheme: "balanced".to_string(),
+            preset_configs,
+            semantic_search: SemanticSearchConfig {
+                threshold: 0.5,
+                storage: EmbeddingStorageConfig::default(),
+                ann: AnnConfig::default(),
+                keywords: KeywordSearchConfig::default(),
+            },
+            encryption: EncryptionConfig {
+                algorithm: "AES-256-CBC".to_string(),
+            },
+            two_factor_authentication: TwoFactorAuthenticationConfig {
+                enabled: false,
+                secret_key: None,
+                issuer: "MoonBall Archiver".to_string(),
+                algorithm: "SHA256".to_string(),
+                period: 30,
+            },
+            compression_algorithms: CompressionAlgorithmsConfig {
+                brotli: BrotliConfig { level: 11 },
+                lzma: LzmaConfig {
+                    preset: "9".to_string(),
+                },
+                zstd: ZstdConfig { level: 19 },
+                lz4: Lz4Config::default(),
+                xz: XzConfig::default(),
+                zstd_long: ZstdLongConfig::default(),
+            },
+            logging: LoggingConfig {
+                file_path: "./logs/moonball.log".to_string(),
+                max_size_mb: 10,
+                backup_count: 5,
+                json: false,
+            },
+            solid: SolidConfig::default(),
+            dictionary: DictionaryConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    fn error_policy(&self) -> ErrorPolicy {
+        match self.error_handling.as_str() {
+            "abort" => ErrorPolicy::Abort,
+            "retry" => ErrorPolicy::Retry,
+            _ => ErrorPolicy::Continue,
+        }
+    }
+
+    /// Worker threads for parallel work; 0 lets rayon use one per CPU.
+    fn thread_count(&self) -> usize {
+        self.parallel_threads.parse().unwrap_or(0)
+    }
+
+    /// Resolves the embedding model for a chunk of a file in `category`. Models the
+    /// embedding script can't load are replaced by `fallback_model`, as are audio
+    /// models for chunks after the first, which lack the file header they decode.
+    fn model_for(&self, category: FileCategory, chunk_id: usize) -> String {
+        let model = self.preset_model(category);
+        let headerless_audio = category == FileCategory::Audio && chunk_id > 0;
+        if embedder_supports(&model) && !headerless_audio {
+            model
+        } else {
+            self.fallback_model.clone()
+        }
+    }
+
+    /// The model used for each file category, plus the fallback. Reindex progress
+    /// only resumes when these match, so every embedding lands in the same space.
+    fn embedding_models(&self) -> Vec<String> {
+        [
+            FileCategory::Text,
+            FileCategory::Image,
+            FileCategory::Audio,
+            FileCategory::Video,
+            FileCategory::StructuredData,
+            FileCategory::Code,
+        ]
+        .iter()
+        .map(|&category| self.model_for(category, 0))
+        .chain(std::iter::once(self.fallback_model.clone()))
+        .collect()
+    }
+
+    /// The model the active preset names for a file category.
+    fn preset_model(&self, category: FileCategory) -> String {
+        let (models, multi_modal) = match self.preset.as_str() {
+            "fallback" => return self.fallback_model.clone(),
+            "custom" => (&self.custom_models, false),
+            preset => match self.preset_configs.get(preset) {
+                Some(preset_config) => (&preset_config.custom_models, preset_config.multi_modal),
+                None => return self.fallback_model.clone(),
+            },
+        };
+
+        // A multi-modal model embeds images into the same space as text
+        if multi_modal && category == FileCategory::Image {
+            return self.multi_modal_model.clone();
+        }
+        category
+            .config_key()
+            .and_then(|key| models.get(key))
+            .cloned()
+            .unwrap_or_else(|| self.fallback_model.clone())
+    }
+
+    /// Checks values serde can't, reporting each problem with its field path.
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, path: &str, message: String| {
+            if !ok {
+                errors.push(format!("{}: {}", path, message));
+            }
+        };
+
+        check(
+            PRESETS.contains(&self.preset.as_str()),
+            "preset",
+            format!(
+                "unknown preset '{}', expected one of {}",
+                self.preset,
+                PRESETS.join(", ")
+            ),
+        );
+        if self.preset != "custom" && self.preset != "fallback" {
+            check(
+                self.preset_configs.contains_key(&self.preset),
+                "preset_configs",
+                format!("has no entry for the active preset '{}'", self.preset),
+            );
+        }
+        for category in self.custom_models.keys() {
+            check(
+                MODEL_CATEGORIES.contains(&category.as_str()),
+                &format!("custom_models.{}", category),
+                format!(
+                    "unknown category, expected one of {}",
+                    MODEL_CATEGORIES.join(", ")
+                ),
+            );
+        }
+        for (preset, preset_config) in &self.preset_configs {
+            for category in preset_config.custom_models.keys() {
+                check(
+                    MODEL_CATEGORIES.contains(&category.as_str()),
+                    &format!("preset_configs.{}.custom_models.{}", preset, category),
+                    "unknown category".to_string(),
+                );
+            }
+        }
+        check(
+            LOGGING_LEVELS.contains(&self.logging_level.to_uppercase().as_str()),
+            "logging_level",
+            format!(
+                "unknown level '{}', expected one of {}",
+                self.logging_level,
+                LOGGING_LEVELS.join(", ")
+            ),
+        );
+        check(
+            self.logging.max_size_mb > 0,
+            "logging.max_size_mb",
+            "must be greater than 0".to_string(),
+        );
+        check(
+            self.parallel_threads == "auto"
+                || self
+                    .parallel_threads
+                    .parse::<usize>()
+                    .is_ok_and(|threads| threads > 0),
+            "parallel_threads",
+            format!(
+                "must be 'auto' or a positive number, got '{}'",
+                self.parallel_threads
+            ),
+        );
+        check(
+            (1..=9).contains(&self.compression_level),
+            "compression_level",
+            format!("must be between 1 and 9, got {}", self.compression_level),
+        );
+        check(
+            self.chunk_size > 0,
+            "chunk_size",
+            "must be greater than 0".to_string(),
+        );
+        check(
+            CHAT_PROVIDERS.contains(&self.chat_provider.as_str()),
+            "chat_provider",
+            format!(
+                "unknown provider '{}', expected one of {}",
+                self.chat_provider,
+                CHAT_PROVIDERS.join(", ")
+            ),
+        );
+        check(
+            ["continue", "abort", "retry"].contains(&self.error_handling.as_str()),
+            "error_handling",
+            format!(
+                "unknown strategy '{}', expected continue, abort or retry",
+                self.error_handling
+            ),
+        );
+        check(
+            self.retry.attempts >= 1,
+            "retry.attempts",
+            "must be at least 1".to_string(),
+        );
+        check(
+            self.retry.backoff_factor >= 1.0,
+            "retry.backoff_factor",
+            format!("must be at least 1.0, got {}", self.retry.backoff_factor),
+        );
+        if let Err(err) = self.scheme.parse::<Scheme>() {
+            check(false, "scheme", err);
+        }
+        check(
+            (-1.0..=1.0).contains(&self.semantic_search.threshold),
+            "semantic_search.threshold",
+            format!(
+                "must be between -1 and 1, got {}",
+                self.semantic_search.threshold
+            ),
+        );
+        check(
+            self.semantic_search.storage.pq_subvectors > 0,
+            "semantic_search.storage.pq_subvectors",
+            "must be greater than 0".to_string(),
+        );
+        let ann = &self.semantic_search.ann;
+        check(
+            (2..=256).contains(&ann.m),
+            "semantic_search.ann.m",
+            format!("must be between 2 and 256, got {}", ann.m),
+        );
+        check(
+            ann.ef_search > 0,
+            "semantic_search.ann.ef_search",
+            "must be greater than 0".to_string(),
+        );
+        check(
+            ann.rerank_factor > 0,
+            "semantic_search.ann.rerank_factor",
+            "must be greater than 0".to_string(),
+        );
+        check(
+            self.semantic_search.keywords.rrf_k >= 0.0,
+            "semantic_search.keywords.rrf_k",
+            "must not be negative".to_string(),
+        );
+        check(
+            self.encryption
+                .algorithm
+                .eq_ignore_ascii_case("AES-256-CBC"),
+            "encryption.algorithm",
+            format!(
+                "unsupported algorithm '{}', expected AES-256-CBC",
+                self.encryption.algorithm
+            ),
+        );
+
+        let two_factor = &self.two_factor_authentication;
+        check(
+            two_factor.period > 0,
+            "two_factor_authentication.period",
+            "must be greater than 0".to_string(),
+        );
+        check(
+            OTP_ALGORITHMS.contains(&two_factor.algorithm.to_uppercase().as_str()),
+            "two_factor_authentication.algorithm",
+            format!(
+                "unsupported algorithm '{}', expected one of {}",
+                two_factor.algorithm,
+                OTP_ALGORITHMS.join(", ")
+            ),
+        );
+        if two_factor.enabled {
+            let secret_valid = two_factor
+                .secret_key
+                .as_ref()
+                .and_then(|secret| base32::decode(RFC4648 { padding: false }, secret))
+                .is_some_and(|secret| !secret.is_empty());
+            check(
+                secret_valid,
+                "two_factor_authentication.secret_key",
+                "must be a base32 secret when 2FA is enabled".to_string(),
+            );
+        }
+
+        let algorithms = &self.compression_algorithms;
+        check(
+            algorithms.brotli.level <= 11,
+            "compression_algorithms.brotli.level",
+            format!("must be between 0 and 11, got {}", algorithms.brotli.level),
+        );
+        check(
+            algorithms
+                .lzma
+                .preset
+                .parse::<u32>()
+                .is_ok_and(|preset| preset <= 9),
+            "compression_algorithms.lzma.preset",
+            format!("must be between 0 and 9, got '{}'", algorithms.lzma.preset),
+        );
+        check(
+            (1..=22).contains(&algorithms.zstd.level),
+            "compression_algorithms.zstd.level",
+            format!("must be between 1 and 22, got {}", algorithms.zstd.level),
+        );
+        check(
+            algorithms.lz4.level <= 12,
+            "compression_algorithms.lz4.level",
+            format!("must be between 0 and 12, got {}", algorithms.lz4.level),
+        );
+        check(
+            algorithms.xz.preset <= 9,
+            "compression_algorithms.xz.preset",
+            format!("must be between 0 and 9, got {}", algorithms.xz.preset),
+        );
+        check(
+            (1..=22).contains(&algorithms.zstd_long.level),
+            "compression_algorithms.zstd_long.level",
+            format!(
+                "must be between 1 and 22, got {}",
+                algorithms.zstd_long.level
+            ),
+        );
+        check(
+            (10..=31).contains(&algorithms.zstd_long.window_log),
+            "compression_algorithms.zstd_long.window_log",
+            format!(
+                "must be between 10 and 31, got {}",
+                algorithms.zstd_long.window_log
+            ),
+        );
+
+        check(
+            self.solid.block_size >= self.solid.small_file_threshold,
+            "solid.block_size",
+            "must be at least solid.small_file_threshold".to_string(),
+        );
+
+        errors
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FallbackProviderSettings {
+    ollama: OllamaSettings,
+    openai: OpenAISettings,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct OllamaSettings {
+    provider: String,
+    model: String,
+    context_length: u32,
+    api_base: String,
+    system_message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct OpenAISettings {
+    provider: String,
+    model: String,
+    context_length: u32,
+    api_key: String,
+    api_base: String,
+    system_message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PresetConfig {
+    custom_models: HashMap<String, String>,
+    multi_modal: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SemanticSearchConfig {
+    threshold: f32,
+    #[serde(default)]
+    storage: EmbeddingStorageConfig,
+    #[serde(default)]
+    ann: AnnConfig,
+    #[serde(default)]
+    keywords: KeywordSearchConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KeywordSearchConfig {
+    enabled: bool,         // Build a BM25 index over text chunks when archiving
+    rrf_k: f32,            // Reciprocal rank fusion constant; larger values flatten the ranking
+    snippet_length: usize, // Characters shown per search hit
+}
+
+impl Default for KeywordSearchConfig {
+    fn default() -> Self {
+        KeywordSearchConfig {
+            enabled: true,
+            rrf_k: 60.0,
+            snippet_length: 160,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AnnConfig {
+    enabled: bool,
+    min_vectors: usize, // Smaller embedding blocks are scanned linearly
+    m: usize,           // Graph neighbors per node (twice as many on the bottom layer)
+    ef_construction: usize,
+    ef_search: usize,
+    rerank: bool,
+    rerank_factor: usize, // Candidates fetched per requested result when re-ranking
+}
+
+impl Default for AnnConfig {
+    fn default() -> Self {
+        AnnConfig {
+            enabled: true,
+            min_vectors: 1024,
+            m: 16,
+            ef_construction: 100,
+            ef_search: 64,
+            rerank: false,
+            rerank_factor: 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EmbeddingStorageConfig {
+    quantization: Quantization,
+    pq_subvectors: usize, // Must divide the embedding dimension
+}
+
+impl Default for EmbeddingStorageConfig {
+    fn default() -> Self {
+        EmbeddingStorageConfig {
+            quantization: Quantization::F16,
+            pq_subvectors: 16,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct EncryptionConfig {
+    algorithm: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TwoFactorAuthenticationConfig {
+    enabled: bool,
+    #[serde(default)]
+    secret_key: Option<String>, // Base32 TOTP secret
+    issuer: String,
+    algorithm: String,
+    period: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CompressionAlgorithmsConfig {
+    brotli: BrotliConfig,
+    lzma: LzmaConfig,
+    zstd: ZstdConfig,
+    #[serde(default)]
+    lz4: Lz4Config,
+    #[serde(default)]
+    xz: XzConfig,
+    #[serde(default)]
+    zstd_long: ZstdLongConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BrotliConfig {
+    level: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LzmaConfig {
+    preset: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ZstdConfig {
+    level: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Lz4Config {
+    level: u32, // 0 is the fastest mode, higher values enable LZ4 HC
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct XzConfig {
+    preset: u32,
+    threads: u32, // 0 uses one thread per CPU
+}
+
+impl Default for XzConfig {
+    fn default() -> Self {
+        XzConfig {
+            preset: 6,
+            threads: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ZstdLongConfig {
+    level: i32,
+    window_log: u32,       // Up to 31; decoders need the same window limit
+    min_chunk_size: usize, // Chunks smaller than this never use long-range mode
+}
+
+impl Default for ZstdLongConfig {
+    fn default() -> Self {
+        ZstdLongConfig {
+            level: 19,
+            window_log: 27,
+            min_chunk_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LoggingConfig {
+    file_path: String, // Empty to log to the console only
+    max_size_mb: u32,  // The file is rotated once it would grow past this
+    backup_count: u32, // Rotated files kept as `<file_path>.1` (newest) to `.<backup_count>`
+    #[serde(default)]
+    json: bool, // Write the file as one JSON object per line
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RetryConfig {
+    attempts: u32, // Including the first try
+    initial_delay_ms: u64,
+    backoff_factor: f64, // Each delay is this many times the previous one
+    max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 3,
+            initial_delay_ms: 500,
+            backoff_factor: 2.0,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before retry number `retry`, counting from 1.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.initial_delay_ms as f64 * self.backoff_factor.powi(retry as i32 - 1);
+        Duration::from_millis(delay.min(self.max_delay_ms as f64) as u64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorPolicy {
+    Continue,
+    Abort,
+    Retry,
+}
+
+/// Result of one file's work under the error policy.
+enum Outcome<T> {
+    Done { value: T, attempts: u32 },
+    Skipped { error: MoonBallError, attempts: u32 },
+}
+
+/// Runs `work` for one file. `abort` returns the first error, `retry` repeats
+/// failed attempts after a growing delay and returns the last error once they
+/// run out, and `continue` turns the error into `Outcome::Skipped`.
+/// Cancellation always ends the run.
+fn run_with_policy<T>(
+    policy: ErrorPolicy,
+    retry: &RetryConfig,
+    file_name: &str,
+    mut work: impl FnMut() -> Result<T, MoonBallError>,
+) -> Result<Outcome<T>, MoonBallError> {
+    let attempts = if policy == ErrorPolicy::Retry {
+        retry.attempts.max(1)
+    } else {
+        1
+    };
+    let mut attempt = 1;
+    loop {
+        match work() {
+            Ok(value) => {
+                return Ok(Outcome::Done {
+                    value,
+                    attempts: attempt,
+                })
+            }
+            Err(MoonBallError::Cancelled) => return Err(MoonBallError::Cancelled),
+            Err(err) if attempt < attempts => {
+                let delay = retry.delay(attempt);
+                log::warn!(
+                    "{}: {}; retrying in {} ms",
+                    file_name,
+                    err,
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(error) if policy == ErrorPolicy::Continue => {
+                return Ok(Outcome::Skipped {
+                    error,
+                    attempts: attempt,
+                })
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// What happened to each file in the last add or extract run.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RunReport {
+    pub operation: String, // "Added" or "Extracted"
+    pub succeeded: usize,
+    pub retried: usize, // Succeeded after more than one attempt
+    pub failures: Vec<Failure>,
+    pub missing_embeddings: usize, // Chunks archived without an embedding
+}
+
+/// A file skipped under `error_handling: continue`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Failure {
+    pub path: String,
+    pub attempts: u32,
+    pub error: String,
+}
+
+impl RunReport {
+    fn new(operation: &str) -> Self {
+        RunReport {
+            operation: operation.to_string(),
+            ..RunReport::default()
+        }
+    }
+
+    fn record<T>(&mut self, path: &str, outcome: Outcome<T>) -> Option<T> {
+        match outcome {
+            Outcome::Done { value, attempts } => {
+                self.succeeded += 1;
+                if attempts > 1 {
+                    self.retried += 1;
+                }
+                Some(value)
+            }
+            Outcome::Skipped { error, attempts } => {
+                log::warn!("Skipping {}: {}", path, error);
+                self.failures.push(Failure {
+                    path: path.to_string(),
+                    attempts,
+                    error: error.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    /// One line of totals, followed by a line per skipped file.
+    pub fn summary(&self) -> String {
+        let mut summary = format!("{} {} files", self.operation, self.succeeded);
+        if self.retried > 0 {
+            summary.push_str(&format!(" ({} after retrying)", self.retried));
+        }
+        if !self.failures.is_empty() {
+            summary.push_str(&format!(", skipped {}:", self.failures.len()));
+            for failure in &self.failures {
+                summary.push_str(&format!(
+                    "\n  {}: {} ({} attempts)",
+                    failure.path, failure.error, failure.attempts
+                ));
+            }
+        }
+        if self.missing_embeddings > 0 {
+            summary.push_str(&format!(
+                "\n{} chunks have no embedding yet; run --reindex --missing to add them",
+                self.missing_embeddings
+            ));
+        }
+        summary
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SolidConfig {
+    small_file_threshold: usize, // Files up to this size are grouped into solid blocks
+    block_size: usize,
+}
+
+impl Default for SolidConfig {
+    fn default() -> Self {
+        SolidConfig {
+            small_file_threshold: 64 * 1024,
+            block_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DictionaryConfig {
+    max_size: usize,
+    sample_size: usize, // Bytes taken from the start of each file when training
+    max_samples: usize,
+}
+
+impl Default for DictionaryConfig {
+    fn default() -> Self {
+        DictionaryConfig {
+            max_size: 112 * 1024,
+            sample_size: 16 * 1024,
+            max_samples: 4096,
+        }
+    }
+}
+
+/// Per-call parameters handed to a codec.
+pub struct CodecParams<'a> {
+    pub level: i32,
+    pub window_log: u32,
+    pub dictionary: Option<&'a [u8]>, // Only passed to codecs that support dictionaries
+    pub original_size: usize,         // Uncompressed size, known when decompressing
+}
+
+/// A compression codec. Built-in codecs are registered automatically; third-party
+/// codecs can be added with `MoonBallArchive::register_codec`.
+pub trait Codec: Send + Sync {
+    /// Stable ID recorded in every chunk; part of the archive format and never reused.
+    fn id(&self) -> u8;
+    fn name(&self) -> &str;
+    fn compress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError>;
+    fn decompress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError>;
+
+    fn supports_dictionary(&self) -> bool {
+        false
+    }
+
+    fn compress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let compressed = self.compress(&data, params)?;
+        writer.write_all(&compressed)?;
+        Ok(compressed.len() as u64)
+    }
+
+    fn decompress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let decompressed = self.decompress(&data, params)?;
+        writer.write_all(&decompressed)?;
+        Ok(decompressed.len() as u64)
+    }
+}
+
+struct ZstdCodec;
+
+impl Codec for ZstdCodec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn supports_dictionary(&self) -> bool {
+        true
+    }
+
+    fn compress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        match params.dictionary {
+            Some(dictionary) => {
+                let mut compressor =
+                    zstd::bulk::Compressor::with_dictionary(params.level, dictionary)?;
+                compressor
+                    .set_parameter(zstd::zstd_safe::CParameter::WindowLog(params.window_log))?;
+                Ok(compressor.compress(data)?)
+            }
+            None => {
+                let mut output = Vec::new();
+                self.compress_stream(&mut &data[..], &mut output, params)?;
+                Ok(output)
+            }
+        }
+    }
+
+    fn decompress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        match params.dictionary {
+            Some(dictionary) => Ok(zstd::bulk::Decompressor::with_dictionary(dictionary)?
+                .decompress(data, params.original_size)?),
+            None => {
+                let mut output = Vec::with_capacity(params.original_size);
+                copy_decode(data, &mut output)?;
+                Ok(output)
+            }
+        }
+    }
+
+    fn compress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut encoder = zstd::stream::Encoder::new(CountingWriter::new(writer), params.level)?;
+        encoder.window_log(params.window_log)?;
+        std::io::copy(reader, &mut encoder)?;
+        Ok(encoder.finish()?.count)
+    }
+}
+
+struct ZstdLongCodec {
+    window_log: u32,
+}
+
+impl Codec for ZstdLongCodec {
+    fn id(&self) -> u8 {
+        6
+    }
+
+    fn name(&self) -> &str {
+        "zstd-long"
+    }
+
+    fn compress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::new();
+        self.compress_stream(&mut &data[..], &mut output, params)?;
+        Ok(output)
+    }
+
+    fn decompress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::with_capacity(params.original_size);
+        self.decompress_stream(&mut &data[..], &mut output, params)?;
+        Ok(output)
+    }
+
+    fn compress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut encoder = zstd::stream::Encoder::new(CountingWriter::new(writer), params.level)?;
+        encoder.long_distance_matching(true)?;
+        encoder.window_log(self.window_log)?;
+        std::io::copy(reader, &mut encoder)?;
+        Ok(encoder.finish()?.count)
+    }
+
+    fn decompress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        _params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut decoder = zstd::stream::Decoder::new(reader)?;
+        decoder.window_log_max(31)?;
+        Ok(std::io::copy(&mut decoder, writer)?)
+    }
+}
+
+struct BrotliCodec;
+
+impl Codec for BrotliCodec {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn name(&self) -> &str {
+        "brotli"
+    }
+
+    fn compress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::new();
+        brotli::CompressorReader::new(data, 4096, params.level as u32, params.window_log.min(24))
+            .read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    fn decompress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::with_capacity(params.original_size);
+        self.decompress_stream(&mut &data[..], &mut output, params)?;
+        Ok(output)
+    }
+
+    fn decompress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        _params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut decompressor = brotli::Decompressor::new(reader, 4096);
+        Ok(std::io::copy(&mut decompressor, writer)?)
+    }
+}
+
+struct LzmaCodec;
+
+impl Codec for LzmaCodec {
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn name(&self) -> &str {
+        "lzma"
+    }
+
+    fn compress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        Ok(lzma::compress(data, params.level as u32)?)
+    }
+
+    fn decompress(&self, data: &[u8], _params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        Ok(lzma::decompress(data)?)
+    }
+}
+
+struct Lz4Codec;
+
+impl Codec for Lz4Codec {
+    fn id(&self) -> u8 {
+        4
+    }
+
+    fn name(&self) -> &str {
+        "lz4"
+    }
+
+    fn compress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::new();
+        self.compress_stream(&mut &data[..], &mut output, params)?;
+        Ok(output)
+    }
+
+    fn decompress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::with_capacity(params.original_size);
+        self.decompress_stream(&mut &data[..], &mut output, params)?;
+        Ok(output)
+    }
+
+    fn compress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut encoder = lz4::EncoderBuilder::new()
+            .level(params.level as u32)
+            .build(CountingWriter::new(writer))?;
+        std::io::copy(reader, &mut encoder)?;
+        let (output, result) = encoder.finish();
+        result?;
+        Ok(output.count)
+    }
+
+    fn decompress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        _params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut decoder = lz4::Decoder::new(reader)?;
+        Ok(std::io::copy(&mut decoder, writer)?)
+    }
+}
+
+struct XzCodec {
+    threads: u32,
+}
+
+impl Codec for XzCodec {
+    fn id(&self) -> u8 {
+        5
+    }
+
+    fn name(&self) -> &str {
+        "xz"
+    }
+
+    fn compress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::new();
+        self.compress_stream(&mut &data[..], &mut output, params)?;
+        Ok(output)
+    }
+
+    fn decompress(&self, data: &[u8], params: &CodecParams) -> Result<Vec<u8>, MoonBallError> {
+        let mut output = Vec::with_capacity(params.original_size);
+        self.decompress_stream(&mut &data[..], &mut output, params)?;
+        Ok(output)
+    }
+
+    fn compress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let threads = if self.threads == 0 {
+            num_cpus::get() as u32
+        } else {
+            self.threads
+        };
+        let stream = xz2::stream::MtStreamBuilder::new()
+            .preset(params.level as u32)
+            .threads(threads)
+            .encoder()?;
+        let mut encoder = xz2::write::XzEncoder::new_stream(CountingWriter::new(writer), stream);
+        std::io::copy(reader, &mut encoder)?;
+        Ok(encoder.finish()?.count)
+    }
+
+    fn decompress_stream(
+        &self,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+        _params: &CodecParams,
+    ) -> Result<u64, MoonBallError> {
+        let mut decoder = xz2::read::XzDecoder::new(reader);
+        Ok(std::io::copy(&mut decoder, writer)?)
+    }
+}
+
+/// Counts the bytes a streaming encoder writes through it.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Codecs available to an archive, keyed by their stable IDs.
+pub struct CodecRegistry {
+    codecs: HashMap<u8, Arc<dyn Codec>>,
+}
+
+impl CodecRegistry {
+    fn with_builtin(config: &Config) -> Self {
+        let algorithms = &config.compression_algorithms;
+        let mut registry = CodecRegistry {
+            codecs: HashMap::new(),
+        };
+        let builtin: Vec<Arc<dyn Codec>> = vec![
+            Arc::new(ZstdCodec),
+            Arc::new(BrotliCodec),
+            Arc::new(LzmaCodec),
+            Arc::new(Lz4Codec),
+            Arc::new(XzCodec {
+                threads: algorithms.xz.threads,
+            }),
+            Arc::new(ZstdLongCodec {
+                window_log: algorithms.zstd_long.window_log,
+            }),
+        ];
+        for codec in builtin {
+            registry.codecs.insert(codec.id(), codec);
+        }
+        registry
+    }
+
+    pub fn register(&mut self, codec: Arc<dyn Codec>) -> Result<(), MoonBallError> {
+        if codec.id() == 0 {
+            // Chunk records use id 0 for codecs looked up by name
+            return Err(format!("Codec '{}' can't use the reserved id 0", codec.name()).into());
+        }
+        if let Some(existing) = self
+            .codecs
+            .values()
+            .find(|existing| existing.id() == codec.id() || existing.name() == codec.name())
+        {
+            return Err(format!(
+                "Codec '{}' (id {}) conflicts with registered codec '{}' (id {})",
+                codec.name(),
+                codec.id(),
+                existing.name(),
+                existing.id()
+            )
+            .into());
+        }
+        self.codecs.insert(codec.id(), codec);
+        Ok(())
+    }
+
+    pub fn get(&self, id: u8) -> Result<&Arc<dyn Codec>, MoonBallError> {
+        self.codecs
+            .get(&id)
+            .ok_or_else(|| MoonBallError::UnsupportedCodec(format!("id {}", id)))
+    }
+
+    pub fn by_name(&self, name: &str) -> Result<&Arc<dyn Codec>, MoonBallError> {
+        self.codecs
+            .values()
+            .find(|codec| codec.name() == name)
+            .ok_or_else(|| MoonBallError::UnsupportedCodec(format!("'{}'", name)))
+    }
+
+    /// Resolves the codec of a stored chunk. Archives written before codec IDs only carry the name.
+    fn for_chunk(&self, chunk: &ChunkMetadata) -> Result<&Arc<dyn Codec>, MoonBallError> {
+        if chunk.codec_id != 0 {
+            self.get(chunk.codec_id)
+        } else {
+            self.by_name(&chunk.compression_algo)
+        }
+    }
+}
+
+/// Kind of content a file holds, used to route its chunks to an embedding model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileCategory {
+    Text,
+    Image,
+    Audio,
+    Video,
+    StructuredData,
+    Code,
+    Other, // Binary data without a dedicated model; uses `fallback_model`
+}
+
+impl FileCategory {
+    /// Key used for the category in `custom_models`.
+    fn config_key(self) -> Option<&'static str> {
+        match self {
+            FileCategory::Text => Some("Text"),
+            FileCategory::Image => Some("Image"),
+            FileCategory::Audio => Some("Audio"),
+            FileCategory::Video => Some("Video"),
+            FileCategory::StructuredData => Some("Structured_Data"),
+            FileCategory::Code => Some("Code"),
+            FileCategory::Other => None,
+        }
+    }
+
+    /// Categories whose chunks are indexed for keyword search.
+    fn is_textual(self) -> bool {
+        matches!(
+            self,
+            FileCategory::Text | FileCategory::Code | FileCategory::StructuredData
+        )
+    }
+
+    /// Detects the category from magic bytes first, then the extension, then whether the content looks like text.
+    fn detect(file_path: &str, head: &[u8]) -> Self {
+        let riff_kind = if head.starts_with(b"RIFF") {
+            head.get(8..12)
+        } else {
+            None
+        };
+        if head.starts_with(b"\x89PNG")
+            || head.starts_with(&[0xFF, 0xD8, 0xFF])
+            || head.starts_with(b"GIF8")
+            || head.starts_with(b"BM")
+            || head.starts_with(b"II*\0")
+            || head.starts_with(b"MM\0*")
+            || riff_kind == Some(b"WEBP")
+        {
+            return FileCategory::Image;
+        }
+        if head.starts_with(b"ID3")
+            || head.starts_with(&[0xFF, 0xFB])
+            || head.starts_with(b"fLaC")
+            || head.starts_with(b"OggS")
+            || riff_kind == Some(b"WAVE")
+        {
+            return FileCategory::Audio;
+        }
+        if head.get(4..8) == Some(b"ftyp")
+            || head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3])
+            || riff_kind == Some(b"AVI ")
+        {
+            return FileCategory::Video;
+        }
+        if head.starts_with(b"PAR1") || head.starts_with(b"SQLite format 3\0") {
+            return FileCategory::StructuredData;
+        }
+
+        let extension = Path::new(file_path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "png" | "jpg" | "jpeg" | "gif" | "bmp" | "webp" | "tif" | "tiff" | "svg" => {
+                return FileCategory::Image
+            }
+            "wav" | "mp3" | "flac" | "ogg" | "m4a" | "aac" | "opus" => return FileCategory::Audio,
+            "mp4" | "mov" | "mkv" | "webm" | "avi" | "m4v" => return FileCategory::Video,
+            "csv" | "tsv" | "json" | "jsonl" | "parquet" | "xlsx" | "xls" | "sqlite" | "db"
+            | "xml" | "yaml" | "yml" | "toml" => return FileCategory::StructuredData,
+            "rs" | "py" | "js" | "ts" | "jsx" | "tsx" | "c" | "h" | "cpp" | "hpp" | "cc"
+            | "java" | "go" | "rb" | "php" | "cs" | "swift" | "kt" | "scala" | "sh" | "bat"
+            | "ps1" | "sql" | "lua" | "moon" => return FileCategory::Code,
+            _ => {}
+        }
+
+        match std::str::from_utf8(head) {
+            Ok(_) => FileCategory::Text,
+            // A multi-byte character may be cut off at the end of the sniffed bytes
+            Err(err) if err.error_len().is_none() => FileCategory::Text,
+            Err(_) => FileCategory::Other,
+        }
+    }
+}
+
+/// Reads the first bytes of a file for `FileCategory::detect`.
+fn sniff_file(file_path: &str) -> Result<Vec<u8>, MoonBallError> {
+    let mut head = Vec::new();
+    File::open(file_path)?.take(4096).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Reversible preprocessing applied to a chunk before it is compressed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Filter {
+    Bcj,                       // x86 CALL/JMP relative targets converted to absolute
+    Delta { distance: usize }, // Byte-wise delta for numeric tables, samples and images
+    Crlf,                      // CRLF line endings stored as LF
+}
+
+impl Filter {
+    fn apply(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            Filter::Bcj => bcj_x86(data, true),
+            Filter::Delta { distance } => {
+                let mut output = data.to_vec();
+                for index in (distance..data.len()).rev() {
+                    output[index] = data[index].wrapping_sub(data[index - distance]);
+                }
+                output
+            }
+            Filter::Crlf => {
+                let mut output = Vec::with_capacity(data.len());
+                for (index, byte) in data.iter().enumerate() {
+                    if *byte != b'\r' || data.get(index + 1) != Some(&b'\n') {
+                        output.push(*byte);
+                    }
+                }
+                output
+            }
+        }
+    }
+
+    fn invert(&self, data: &[u8]) -> Vec<u8> {
+        match *self {
+            Filter::Bcj => bcj_x86(data, false),
+            Filter::Delta { distance } => {
+                let mut output = data.to_vec();
+                for index in distance..output.len() {
+                    output[index] = output[index].wrapping_add(output[index - distance]);
+                }
+                output
+            }
+            Filter::Crlf => {
+                let mut output = Vec::with_capacity(data.len() + data.len() / 32);
+                for byte in data {
+                    if *byte == b'\n' {
+                        output.push(b'\r');
+                    }
+                    output.push(*byte);
+                }
+                output
+            }
+        }
+    }
+}
+
+/// Simplified BCJ: every E8/E9 operand is converted, and operands are skipped in
+/// both directions, so the opcode positions seen by the decoder always match.
+fn bcj_x86(data: &[u8], encode: bool) -> Vec<u8> {
+    let mut output = data.to_vec();
+    let mut index = 0;
+    while index + 5 <= output.len() {
+        if output[index] == 0xE8 || output[index] == 0xE9 {
+            let operand = i32::from_le_bytes(output[index + 1..index + 5].try_into().unwrap());
+            let position = (index + 5) as i32;
+            let converted = if encode {
+                operand.wrapping_add(position)
+            } else {
+                operand.wrapping_sub(position)
+            };
+            output[index + 1..index + 5].copy_from_slice(&converted.to_le_bytes());
+            index += 5;
+        } else {
+            index += 1;
+        }
+    }
+    output
+}
+
+fn apply_filters(chunk: &[u8], filters: &[Filter]) -> Vec<u8> {
+    filters
+        .iter()
+        .fold(chunk.to_vec(), |data, filter| filter.apply(&data))
+}
+
+fn invert_filters(data: Vec<u8>, filters: &[Filter]) -> Vec<u8> {
+    filters
+        .iter()
+        .rev()
+        .fold(data, |data, filter| filter.invert(&data))
+}
+
+/// Order-0 entropy in bits per byte.
+fn byte_entropy(data: &[u8]) -> f32 {
+    if data.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for byte in data {
+        counts[*byte as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f32 / data.len() as f32;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+struct EncodedChunk {
+    algo: String,
+    codec_id: u8,
+    dictionary_id: Option<u32>,
+    filters: Vec<Filter>,
+    data: Vec<u8>,
+}
+
+/// An archive opened for the MCP server, named by its file name.
+struct ServedArchive {
+    name: String,
+    chain: Vec<(String, ArchiveMetadata)>,
+}
+
+impl MoonBallArchive {
+    /// Creates an archive using the discovered configuration layers.
+    pub fn new() -> Result<Self, MoonBallError> {
+        Ok(Self::with_config(load_config(&ConfigSources::default())?))
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        let scheme = config.scheme.parse().unwrap_or_else(|err| {
+            error!("{}; falling back to balanced", err);
+            Scheme::Balanced
+        });
+        let settings = SchemeSettings::resolve(scheme, &config);
+        let codecs = CodecRegistry::with_builtin(&config);
+        MoonBallArchive {
+            metadata: ArchiveMetadata {
+                chunks: Vec::new(),
+                encryption_enabled: false,
+                requires_2fa: config.two_factor_authentication.enabled,
+                secret_key: config
+                    .two_factor_authentication
+                    .secret_key
+                    .clone()
+                    .filter(|secret| !secret.is_empty()),
+                otp_period: Some(config.two_factor_authentication.period as u64),
+                otp_algorithm: Some(config.two_factor_authentication.algorithm.to_uppercase()),
+                base_archive: None,
+                solid_blocks: Vec::new(),
+                dictionaries: Vec::new(),
+                scheme: Some(scheme),
+                embedding_spaces: Vec::new(),
+                embedding_blocks: Vec::new(),
+                ann_indexes: Vec::new(),
+                sections: Vec::new(),
+            },
+            files: Mutex::new(HashMap::new()),
+            base_chunks: HashMap::new(),
+            solid_enabled: false,
+            solid_block: Vec::new(),
+            solid_members: Vec::new(),
+            dictionary: None,
+            settings,
+            codecs,
+            extra_codecs: Vec::new(),
+            keyword_index: KeywordIndex::default(),
+            embeddings_enabled: true,
+            embedding_failed: false,
+            otp: None,
+            observer: None,
+            cancellation: CancellationToken::new(),
+            progress: Mutex::new(ProgressTracker::new("Compressing", 0, 0)),
+            report: Mutex::new(RunReport::default()),
+            config,
+        }
+    }
+
+    /// Turns this archive into a snapshot of `base_path`: chunks whose hash is
+    /// already stored somewhere in the base chain are referenced instead of stored.
+    pub fn set_snapshot_base(&mut self, base_path: &str) -> Result<(), MoonBallError> {
+        for (path, mut base_metadata) in self.open_chain(base_path)? {
+            self.unpack_embeddings(&path, &mut base_metadata)?;
+            let base_spaces = base_metadata.embedding_spaces;
+            for mut chunk in base_metadata.chunks {
+                // Reused embeddings keep their identity, under this archive's space IDs
+                let base_space = chunk
+                    .embedding_space
+                    .and_then(|id| base_spaces.iter().find(|space| space.id == id));
+                chunk.embedding_space = base_space.map(|space| {
+                    let embedding = Embedding {
+                        vector: vec![0.0; space.dimension],
+                        model: space.model.clone(),
+                        version: space.version.clone(),
+                    };
+                    self.metadata.embedding_space_for(&embedding)
+                });
+                self.base_chunks.entry(chunk.hash.clone()).or_insert(chunk);
+            }
+        }
+        self.metadata.base_archive = Some(base_path.to_string());
+        info!(
+            "Snapshot base {} provides {} chunks",
+            base_path,
+            self.base_chunks.len()
+        );
+        Ok(())
+    }
+
+    /// Makes a third-party codec available for extraction and, when `compress` is
+    /// set, adds it to the front of the candidates the selector picks from.
+    pub fn register_codec(
+        &mut self,
+        codec: Arc<dyn Codec>,
+        compress: bool,
+    ) -> Result<(), MoonBallError> {
+        let name = codec.name().to_string();
+        self.codecs.register(codec)?;
+        if compress {
+            self.settings.algorithms.insert(0, name.clone());
+            self.extra_codecs.insert(0, name);
+        }
+        Ok(())
+    }
+
+    pub fn set_scheme(&mut self, scheme: Scheme) {
+        self.settings = SchemeSettings::resolve(scheme, &self.config);
+        self.settings
+            .algorithms
+            .splice(0..0, self.extra_codecs.iter().cloned());
+        self.metadata.scheme = Some(scheme);
+    }
+
+    /// Encrypts chunks and the index. Must be called before any file is added.
+    pub fn enable_encryption(&mut self) {
+        self.metadata.encryption_enabled = true;
+    }
+
+    /// Turns the 2FA requirement on or off, overriding the configuration. When
+    /// enabled, returns the base32 secret to enrol in an authenticator app,
+    /// generating one if none is configured.
+    pub fn set_two_factor(&mut self, enabled: bool) -> Option<String> {
+        self.metadata.requires_2fa = enabled;
+        if !enabled {
+            self.metadata.secret_key = None;
+            return None;
+        }
+        let secret = self.metadata.secret_key.get_or_insert_with(|| {
+            base32::encode(
+                RFC4648 { padding: false },
+                &rand::thread_rng().gen::<[u8; 20]>(),
+            )
+        });
+        Some(secret.clone())
+    }
+
+    /// Supplies the one-time password for 2FA archives instead of prompting on stdin.
+    pub fn set_otp(&mut self, otp: &str) {
+        self.otp = Some(otp.trim().to_string());
+    }
+
+    /// Reports the progress of adding, saving and extracting to `observer`.
+    pub fn set_observer(&mut self, observer: Arc<dyn ProgressObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Replaces the token checked between chunks, so a caller can create it before the archive.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation = token;
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Files that succeeded, were retried or were skipped in the last `add_paths`
+    /// or extraction.
+    pub fn report(&self) -> RunReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn begin_progress(&self, operation: &str, bytes_total: u64, chunks_total: usize) {
+        *self.progress.lock().unwrap() = ProgressTracker::new(operation, bytes_total, chunks_total);
+    }
+
+    /// Counts a finished chunk of `file_name`, notifies the observer and stops
+    /// the operation if it was cancelled.
+    fn advance(&self, file_name: &str, bytes: u64) -> Result<(), MoonBallError> {
+        if let Some(observer) = &self.observer {
+            let mut tracker = self.progress.lock().unwrap();
+            tracker.progress.elapsed = tracker.started.elapsed();
+            let progress = &mut tracker.progress;
+            if progress.current_file != file_name {
+                progress.current_file = file_name.to_string();
+            }
+            progress.bytes_done += bytes;
+            progress.chunks_done += 1;
+            observer.on_progress(progress);
+        }
+        if self.cancellation.is_cancelled() {
+            return Err(MoonBallError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn finish_progress(&self) {
+        if let Some(observer) = &self.observer {
+            observer.on_finish(&self.progress.lock().unwrap().progress);
+        }
+    }
+
+    /// Groups small files into shared solid blocks instead of compressing them one by one.
+    pub fn enable_solid_mode(&mut self) {
+        self.solid_enabled = true;
+    }
+
+    /// Archives chunks without computing embeddings. They are flagged so a later
+    /// `--reindex` can add them.
+    pub fn disable_embeddings(&mut self) {
+        self.embeddings_enabled = false;
+    }
+
+    /// Trains a zstd dictionary from the start of each file under `paths`. The
+    /// dictionary is stored in the archive and used for every zstd chunk added afterwards.
+    pub fn train_dictionary(&mut self, paths: &[&str]) -> Result<(), MoonBallError> {
+        let mut files = Vec::new();
+        for path in paths {
+            collect_files(Path::new(path), &mut files)?;
+        }
+
+        let mut samples = Vec::new();
+        for file_path in files.iter().take(self.config.dictionary.max_samples) {
+            let mut sample = Vec::new();
+            File::open(file_path)?
+                .take(self.config.dictionary.sample_size as u64)
+                .read_to_end(&mut sample)?;
+            if !sample.is_empty() {
+                samples.push(sample);
+            }
+        }
+
+        // zstd needs a reasonable number of samples before a dictionary pays off
+        if samples.len() < 8 {
+            info!(
+                "Skipping dictionary training: only {} samples available",
+                samples.len()
+            );
+            return Ok(());
+        }
+
+        let dictionary = zstd::dict::from_samples(&samples, self.config.dictionary.max_size)?;
+        let id = self.metadata.dictionaries.len() as u32 + 1;
+        self.metadata.dictionaries.push(DictionaryMetadata {
+            id,
+            data: base64::encode(&dictionary),
+        });
+        info!(
+            "Trained {} byte dictionary from {} samples",
+            dictionary.len(),
+            samples.len()
+        );
+        self.dictionary = Some((id, dictionary));
+
+        Ok(())
+    }
+
+    /// Adds files and directories, reporting progress against their combined size.
+    pub fn add_paths(&mut self, paths: &[&str]) -> Result<(), MoonBallError> {
+        let mut files = Vec::new();
+        for path in paths {
+            collect_files(Path::new(path), &mut files)?;
+        }
+        let bytes_total = files
+            .iter()
+            .map(|file_path| std::fs::metadata(file_path).map_or(0, |metadata| metadata.len()))
+            .sum();
+
+        self.begin_progress("Compressing", bytes_total, 0);
+        let (policy, retry) = (self.config.error_policy(), self.config.retry.clone());
+        let mut report = RunReport::new("Added");
+        for file_path in &files {
+            let outcome = run_with_policy(policy, &retry, file_path, || {
+                let checkpoint = self.checkpoint();
+                let result = self.add_file(file_path);
+                if result.is_err() {
+                    self.rollback(&checkpoint);
+                }
+                result
+            });
+            match outcome {
+                Ok(outcome) => {
+                    report.record(file_path, outcome);
+                }
+                Err(err) => {
+                    *self.report.lock().unwrap() = report;
+                    return Err(err);
+                }
+            }
+        }
+        report.missing_embeddings = self
+            .metadata
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.embedding_pending)
+            .count();
+        *self.report.lock().unwrap() = report;
+        self.finish_progress();
+
+        Ok(())
+    }
+
+    fn checkpoint(&self) -> AddCheckpoint {
+        AddCheckpoint {
+            chunks: self.metadata.chunks.len(),
+            solid_members: self.solid_members.len(),
+            solid_block: self.solid_block.len(),
+            keyword_documents: self.keyword_index.documents.len(),
+        }
+    }
+
+    /// Drops everything a failed file added after `checkpoint`.
+    fn rollback(&mut self, checkpoint: &AddCheckpoint) {
+        let mut files = self.files.lock().unwrap();
+        for chunk in self.metadata.chunks.drain(checkpoint.chunks..) {
+            files.remove(&chunk_key(&chunk.file_name, chunk.chunk_id));
+        }
+        drop(files);
+        self.solid_members.truncate(checkpoint.solid_members);
+        self.solid_block.truncate(checkpoint.solid_block);
+        self.keyword_index.truncate(checkpoint.keyword_documents);
+    }
+
+    pub fn add_directory(&mut self, dir_path: &str) -> Result<(), MoonBallError> {
+        let mut files = Vec::new();
+        collect_files(Path::new(dir_path), &mut files)?;
+        for file_path in files {
+            self.add_file(&file_path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn add_file(&mut self, file_path: &str) -> Result<(), MoonBallError> {
+        if self.solid_enabled
+            && std::fs::metadata(file_path)?.len() as usize
+                <= self.config.solid.small_file_threshold
+        {
+            let data = std::fs::read(file_path)?;
+            self.add_to_solid_block(&data, file_path)?;
+            if FileCategory::detect(file_path, &data[..data.len().min(4096)]).is_textual() {
+                self.keyword_index
+                    .add(self.metadata.chunks.len() - 1, &data);
+            }
+            return self.advance(file_path, data.len() as u64);
+        }
+
+        let category = FileCategory::detect(file_path, &sniff_file(file_path)?);
+
+        let file = File::open(file_path)?;
+        let file_size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let chunking = self
+            .settings
+            .chunking_for(file_size, &self.config.compression_algorithms.zstd_long);
+        let mut buffer = vec![0; chunking.read_size()];
+        let mut pending = Vec::new();
+        let mut chunk_id = 0;
+
+        loop {
+            let size = reader.read(&mut buffer)?;
+            let eof = size == 0;
+            pending.extend_from_slice(&buffer[..size]);
+
+            while let Some(cut) = chunking.cut_point(&pending, eof) {
+                let rest = pending.split_off(cut);
+                let model = self.config.model_for(category, chunk_id);
+                self.compress_chunk(&pending, file_path, chunk_id, &model)?;
+                if category.is_textual() {
+                    self.keyword_index
+                        .add(self.metadata.chunks.len() - 1, &pending);
+                }
+                self.advance(file_path, pending.len() as u64)?;
+                pending = rest;
+                chunk_id += 1;
+            }
+
+            if eof {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unchanged since the base archive: keep a reference and reuse its embedding.
+    fn base_reference(
+        &self,
+        hash: &str,
+        file_path: &str,
+        chunk_id: usize,
+        original_size: usize,
+    ) -> Option<ChunkMetadata> {
+        let base_chunk = self.base_chunks.get(hash)?;
+        Some(ChunkMetadata {
+            file_name: file_path.to_string(),
+            chunk_id,
+            original_size,
+            compressed_size: base_chunk.compressed_size,
+            compression_algo: base_chunk.compression_algo.clone(),
+            codec_id: base_chunk.codec_id,
+            embedding: base_chunk.embedding.clone(),
+            embedding_model: base_chunk.embedding_model.clone(),
+            embedding_space: base_chunk.embedding_space,
+            embedding_pending: base_chunk.embedding_pending,
+            hash: hash.to_string(),
+            offset: 0,
+            base_ref: true,
+            solid: base_chunk.solid.clone(),
+            dictionary_id: base_chunk.dictionary_id,
+            filters: base_chunk.filters.clone(),
+        })
+    }
+
+    fn compress_chunk(
+        &mut self,
+        chunk: &[u8],
+        file_path: &str,
+        chunk_id: usize,
+        model: &str,
+    ) -> Result<(), MoonBallError> {
+        let _log = LogScope::chunk(file_path, chunk_id);
+        let hash = chunk_hash(chunk);
+        if let Some(reference) = self.base_reference(&hash, file_path, chunk_id, chunk.len()) {
+            self.metadata.chunks.push(reference);
+            return Ok(());
+        }
+
+        let encoded = self.encode_chunk(chunk, file_path)?;
+        let embedding = self.embed(chunk, file_path, model)?;
+        let embedding_pending = embedding.is_none();
+        let embedding_space = embedding
+            .as_ref()
+            .map(|embedding| self.metadata.embedding_space_for(embedding));
+        let (embedding, embedding_model) = embedding.map_or((None, None), |embedding| {
+            (Some(embedding.vector), Some(embedding.model))
+        });
+
+        let metadata = ChunkMetadata {
+            file_name: file_path.to_string(),
+            chunk_id,
+            original_size: chunk.len(),
+            compressed_size: encoded.data.len(),
+            compression_algo: encoded.algo,
+            codec_id: encoded.codec_id,
+            embedding,
+            embedding_model,
+            embedding_space,
+            embedding_pending,
+            hash,
+            offset: 0,
+            base_ref: false,
+            solid: None,
+            dictionary_id: encoded.dictionary_id,
+            filters: encoded.filters,
+        };
+
+        self.metadata.chunks.push(metadata);
+
+        // Save compressed data to memory cache
+        let mut files = self.files.lock().unwrap();
+        files.insert(chunk_key(file_path, chunk_id), encoded.data);
+
+        Ok(())
+    }
+
+    fn add_to_solid_block(&mut self, data: &[u8], file_path: &str) -> Result<(), MoonBallError> {
+        let _log = LogScope::chunk(file_path, 0);
+        let hash = chunk_hash(data);
+        if let Some(reference) = self.base_reference(&hash, file_path, 0, data.len()) {
+            self.metadata.chunks.push(reference);
+            return Ok(());
+        }
+
+        // Embeddings stay per file; only the compression is shared across the block
+        let model = self.config.model_for(
+            FileCategory::detect(file_path, &data[..data.len().min(4096)]),
+            0,
+        );
+        let embedding = self.embed(data, file_path, &model)?;
+        let embedding_pending = embedding.is_none();
+        let embedding_space = embedding
+            .as_ref()
+            .map(|embedding| self.metadata.embedding_space_for(embedding));
+        let (embedding, embedding_model) = embedding.map_or((None, None), |embedding| {
+            (Some(embedding.vector), Some(embedding.model))
+        });
+        self.metadata.chunks.push(ChunkMetadata {
+            file_name: file_path.to_string(),
+            chunk_id: 0,
+            original_size: data.len(),
+            compressed_size: 0,
+            compression_algo: String::new(),
+            codec_id: 0,
+            embedding,
+            embedding_model,
+            embedding_space,
+            embedding_pending,
+            hash,
+            offset: 0,
+            base_ref: false,
+            solid: Some(SolidSlice {
+                block_hash: String::new(), // Filled in once the block is flushed
+                offset: self.solid_block.len(),
+            }),
+            dictionary_id: None,
+            filters: Vec::new(),
+        });
+        self.solid_members.push(self.metadata.chunks.len() - 1);
+        self.solid_block.extend_from_slice(data);
+
+        if self.solid_block.len() >= self.config.solid.block_size {
+            self.flush_solid_block()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_solid_block(&mut self) -> Result<(), MoonBallError> {
+        if self.solid_block.is_empty() {
+            return Ok(());
+        }
+
+        // Encode before taking the block so a failure leaves it intact for rollback
+        let encoded = self.encode_chunk(&self.solid_block, "")?;
+        let block = std::mem::take(&mut self.solid_block);
+        let hash = chunk_hash(&block);
+
+        for index in self.solid_members.drain(..) {
+            let member = &mut self.metadata.chunks[index];
+            member.compression_algo = encoded.algo.clone();
+            member.codec_id = encoded.codec_id;
+            member.dictionary_id = encoded.dictionary_id;
+            member.filters = encoded.filters.clone();
+            if let Some(solid) = member.solid.as_mut() {
+                solid.block_hash = hash.clone();
+            }
+        }
+
+        let block_id = self.metadata.solid_blocks.len();
+        self.metadata.solid_blocks.push(ChunkMetadata {
+            file_name: String::new(),
+            chunk_id: block_id,
+            original_size: block.len(),
+            compressed_size: encoded.data.len(),
+            compression_algo: encoded.algo,
+            codec_id: encoded.codec_id,
+            embedding: None,
+            embedding_model: None,
+            embedding_space: None,
+            embedding_pending: false, // Blocks are never embedded, only their members
+            hash,
+            offset: 0,
+            base_ref: false,
+            solid: None,
+            dictionary_id: encoded.dictionary_id,
+            filters: encoded.filters,
+        });
+
+        let mut files = self.files.lock().unwrap();
+        files.insert(chunk_key("", block_id), encoded.data);
+
+        Ok(())
+    }
+
+    /// Filters, compresses and (if enabled) encrypts a chunk with the codec the scheme selects.
+    fn encode_chunk(&self, chunk: &[u8], file_path: &str) -> Result<EncodedChunk, MoonBallError> {
+        let (algo, filters) = self.predict_compression_algo(chunk, file_path);
+        let filtered = apply_filters(chunk, &filters);
+
+        let mut encoded = if self.settings.try_all {
+            let mut best: Option<EncodedChunk> = None;
+            for algo in &self.settings.algorithms {
+                let candidate = self.compress_with(algo, &filtered)?;
+                if best
+                    .as_ref()
+                    .is_none_or(|smallest| candidate.data.len() < smallest.data.len())
+                {
+                    best = Some(candidate);
+                }
+            }
+            best.ok_or("Compression scheme has no algorithms")?
+        } else {
+            self.compress_with(&algo, &filtered)?
+        };
+        encoded.filters = filters;
+
+        if self.metadata.encryption_enabled {
+            encoded.data = self.encrypt_data(&encoded.data)?;
+        }
+
+        Ok(encoded)
+    }
+
+    fn compress_with(&self, algo: &str, chunk: &[u8]) -> Result<EncodedChunk, MoonBallError> {
+        let codec = self.codecs.by_name(algo)?;
+        let dictionary = self
+            .dictionary
+            .as_ref()
+            .filter(|_| codec.supports_dictionary());
+        let params = CodecParams {
+            level: self.settings.level_for(algo, &self.config),
+            window_log: self.settings.window_log,
+            dictionary: dictionary.map(|(_, bytes)| bytes.as_slice()),
+            original_size: chunk.len(),
+        };
+
+        Ok(EncodedChunk {
+            algo: algo.to_string(),
+            codec_id: codec.id(),
+            dictionary_id: dictionary.map(|(id, _)| *id),
+            filters: Vec::new(),
+            data: codec.compress(chunk, &params)?,
+        })
+    }
+
+    /// Embeds a chunk for archiving. Failures never stop the archive: under `retry`
+    /// the embedding is attempted again first, and whatever still fails returns
+    /// `None` so the chunk is stored without one and flagged for `--reindex`.
+    /// Also `None` when embeddings are turned off. Only cancellation is an error.
+    fn embed(
+        &mut self,
+        chunk: &[u8],
+        file_path: &str,
+        model: &str,
+    ) -> Result<Option<Embedding>, MoonBallError> {
+        if !self.embeddings_enabled {
+            return Ok(None);
+        }
+        let policy = self.config.error_policy();
+        let err = match run_with_policy(policy, &self.config.retry, file_path, || {
+            self.generate_embedding(chunk, model)
+        }) {
+            Ok(Outcome::Done { value, .. }) => return Ok(Some(value)),
+            Ok(Outcome::Skipped { error, .. }) => error,
+            Err(MoonBallError::Cancelled) => return Err(MoonBallError::Cancelled),
+            Err(err) => err,
+        };
+        if !self.embedding_failed {
+            log::warn!("Archiving {} without embeddings: {}. Further failures are only counted in the summary", file_path, err);
+            self.embedding_failed = true;
+        }
+        Ok(None)
+    }
+
+    /// Runs the embedding script for `model`. The chunk is passed base64 encoded on
+    /// stdin since large chunks don't fit in a command line.
+    fn generate_embedding(&self, chunk: &[u8], model: &str) -> Result<Embedding, MoonBallError> {
+        let mut child = Command::new("python3")
+            .arg("generate_embedding.py")
+            .arg("--model")
+            .arg(model)
+            .arg("--model-dir")
+            .arg(&self.config.model_download_path)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                MoonBallError::Embedding(format!("could not start generate_embedding.py: {}", err))
+            })?;
+
+        let base64_chunk = base64::encode(chunk);
+        child
+            .stdin
+            .take()
+            .ok_or("Embedding process has no stdin")?
+            .write_all(base64_chunk.as_bytes())?;
+        let output = child.wait_with_output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(MoonBallError::Embedding(format!(
+                "{} failed: {}",
+                model,
+                stderr.trim()
+            )));
+        }
+
+        #[derive(Deserialize)]
+        struct EmbedderOutput {
+            embedding: Vec<f32>,
+            version: String,
+        }
+
+        let output: EmbedderOutput = serde_json::from_slice(&output.stdout).map_err(|err| {
+            MoonBallError::Embedding(format!("unreadable output for {}: {}", model, err))
+        })?;
+        if output.embedding.is_empty() {
+            return Err(MoonBallError::Embedding(format!(
+                "{} returned an empty embedding",
+                model
+            )));
+        }
+
+        Ok(Embedding {
+            vector: l2_normalize(output.embedding),
+            model: model.to_string(),
+            version: output.version,
+        })
+    }
+
+    /// Hybrid search: fuses the semantic and BM25 keyword rankings with reciprocal
+    /// rank fusion and attaches a snippet to each hit. Archives without a keyword
+    /// index, or without embeddings an installed model can match, are searched with
+    /// whichever ranking is available.
+    pub fn search(
+        &self,
+        archive_path: &str,
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<SearchHit>, MoonBallError> {
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+        self.search_chain(&chain, query, top_k)
+    }
+
+    /// `search` over a chain the caller has already opened and checked the OTP for.
+    fn search_chain(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<SearchHit>, MoonBallError> {
+        let archive_path = chain[0].0.as_str();
+        let _log = LogScope::archive(archive_path);
+        let settings = &self.config.semantic_search.keywords;
+        let pool = top_k * 3; // Candidates taken from each ranking before fusion
+        let metadata = &chain[0].1;
+
+        let keyword_index = match self.read_section(archive_path, metadata, "keywords")? {
+            Some(data) => Some(KeywordIndex::from_bytes(&data)?),
+            None => None,
+        };
+        let semantic = match self.rank_by_meaning(archive_path, metadata, query, pool) {
+            Ok(hits) => hits,
+            Err(err) if keyword_index.is_some() => {
+                log::warn!("Falling back to keyword search: {}", err);
+                Vec::new()
+            }
+            Err(err) => return Err(err),
+        };
+        let keyword = keyword_index.map_or_else(Vec::new, |index| index.search(query, pool));
+
+        let semantic = semantic
+            .iter()
+            .map(|hit| (hit.file_name.as_str(), hit.chunk_id))
+            .collect();
+        let keyword = keyword
+            .iter()
+            .map(|&(position, _)| {
+                let chunk = &metadata.chunks[position];
+                (chunk.file_name.as_str(), chunk.chunk_id)
+            })
+            .collect();
+        let mut fused = reciprocal_rank_fusion(vec![semantic, keyword], settings.rrf_k);
+        fused.truncate(top_k);
+
+        let mut block_cache = None;
+        let mut hits = Vec::with_capacity(fused.len());
+        for ((file_name, chunk_id), score) in fused {
+            let chunk = metadata.find_chunk(file_name, chunk_id).ok_or_else(|| {
+                format!(
+                    "Chunk {} of {} is missing from the index",
+                    chunk_id, file_name
+                )
+            })?;
+            let mut hit = SearchHit::new(metadata, chunk, score);
+            hit.snippet = match self.load_chunk(chain, chunk, &mut block_cache) {
+                Ok(data) => snippet(&data, query, settings.snippet_length),
+                Err(err) => {
+                    log::warn!(
+                        "No snippet for chunk {} of {}: {}",
+                        chunk_id,
+                        file_name,
+                        err
+                    );
+                    None
+                }
+            };
+            hits.push(hit);
+        }
+        Ok(hits)
+    }
+
+    /// Answers `question` from the archive: the top-k search hits are packed into
+    /// the prompt, in rank order, until the provider's context length is used up.
+    pub fn ask(
+        &self,
+        archive_path: &str,
+        question: &str,
+        top_k: usize,
+    ) -> Result<Answer, MoonBallError> {
+        let client = ChatClient::from_config(&self.config)?;
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+        self.answer(&client, archive_path, &chain, &[], question, top_k)
+    }
+
+    /// Answers the next question of a conversation. Fresh excerpts are retrieved
+    /// for every question; earlier turns are kept as plain text and the oldest are
+    /// dropped when they no longer fit next to the excerpts.
+    fn answer(
+        &self,
+        client: &ChatClient,
+        archive_path: &str,
+        chain: &[(String, ArchiveMetadata)],
+        history: &[ChatMessage],
+        question: &str,
+        top_k: usize,
+    ) -> Result<Answer, MoonBallError> {
+        let available = client
+            .prompt_budget()
+            .saturating_sub(estimate_tokens(question) + 64);
+        let history_tokens: usize = history
+            .iter()
+            .map(|message| estimate_tokens(&message.content))
+            .sum();
+        let context_budget = available - history_tokens.min(available / 2);
+
+        let hits = self.search_chain(chain, question, top_k)?;
+        let (context, sources) = self.build_context(chain, &hits, context_budget)?;
+        if sources.is_empty() {
+            return Err(format!("Nothing in {} matches the question", archive_path).into());
+        }
+
+        let prompt = format!(
+            "Answer the question using the numbered excerpts from the archive below. \
+             Cite the excerpts you rely on as [n]. If they don't contain the answer, say so.\n\n{}\nQuestion: {}",
+            context, question
+        );
+        let mut messages =
+            trim_history(history, available.saturating_sub(estimate_tokens(&context))).to_vec();
+        messages.push(ChatMessage::new("user", prompt));
+        let text = client.chat(&messages)?;
+        Ok(Answer {
+            sources: mark_cited(sources, &text),
+            text,
+        })
+    }
+
+    /// Lets the configured model work on `task` by calling the archive tools from
+    /// `archive_tools` until it gives a final answer.
+    pub fn run_agent(&self, archive_path: &str, task: &str) -> Result<String, MoonBallError> {
+        let client = ChatClient::from_config(&self.config)?;
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+
+        let tools: Vec<serde_json::Value> = archive_tools()
+            .iter()
+            .map(ToolDefinition::to_openai)
+            .collect();
+        let mut messages = vec![
+            serde_json::json!({ "role": "system", "content": client.system_message }),
+            serde_json::json!({ "role": "user", "content": task }),
+        ];
+
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let message = client.complete_with_tools(&messages, &tools)?;
+            let calls = message["tool_calls"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            if calls.is_empty() {
+                return Ok(message["content"].as_str().unwrap_or_default().to_string());
+            }
+            messages.push(message);
+
+            for call in calls {
+                let name = call["function"]["name"].as_str().unwrap_or_default();
+                // OpenAI sends arguments as a JSON string, some local servers as an object
+                let arguments = match &call["function"]["arguments"] {
+                    serde_json::Value::String(raw) => {
+                        serde_json::from_str(raw).unwrap_or(serde_json::Value::Null)
+                    }
+                    other => other.clone(),
+                };
+                info!("Tool call {}({})", name, arguments);
+                let output = self
+                    .call_tool(&chain, name, &arguments)
+                    .unwrap_or_else(|err| serde_json::json!({ "error": err.to_string() }));
+                messages.push(serde_json::json!({
+                    "role": "tool",
+                    "tool_call_id": call["id"],
+                    "name": name,
+                    "content": truncate_output(output.to_string()),
+                }));
+            }
+        }
+
+        Err(format!(
+            "The model gave no final answer after {} rounds of tool calls",
+            MAX_TOOL_ROUNDS
+        )
+        .into())
+    }
+
+    /// Runs one of the tools from `archive_tools` against an opened archive chain.
+    fn call_tool(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        name: &str,
+        arguments: &serde_json::Value,
+    ) -> Result<serde_json::Value, MoonBallError> {
+        use serde_json::json;
+        let metadata = &chain[0].1;
+        let string = |key: &str| {
+            arguments[key]
+                .as_str()
+                .ok_or_else(|| format!("Missing string argument '{}'", key))
+        };
+        let number = |key: &str, default: usize| {
+            arguments[key]
+                .as_u64()
+                .map_or(default, |value| value as usize)
+        };
+
+        match name {
+            "list_files" => {
+                let prefix = arguments["prefix"].as_str().unwrap_or("");
+                let limit = number("limit", 200);
+                let files = metadata.file_summaries();
+                let matching: Vec<&FileSummary> = files
+                    .iter()
+                    .filter(|file| file.path.starts_with(prefix))
+                    .collect();
+                Ok(json!({
+                    "files": matching.iter().take(limit).collect::<Vec<_>>(),
+                    "total": matching.len(),
+                    "truncated": matching.len() > limit,
+                }))
+            }
+            "search" => Ok(json!(self.search_chain(
+                chain,
+                string("query")?,
+                number("top_k", 10)
+            )?)),
+            "read_chunk" => {
+                let path = string("path")?;
+                let chunk_id = number("chunk", 0);
+                let chunk = metadata
+                    .find_chunk(path, chunk_id)
+                    .ok_or_else(|| format!("{} has no chunk {}", path, chunk_id))?;
+                let chunks = metadata
+                    .chunks
+                    .iter()
+                    .filter(|chunk| chunk.file_name == path)
+                    .count();
+                let data = self.load_chunk(chain, chunk, &mut None)?;
+                if data.contains(&0) {
+                    return Ok(
+                        json!({ "path": path, "chunk": chunk_id, "chunks": chunks, "binary": true, "size": data.len() }),
+                    );
+                }
+                Ok(
+                    json!({ "path": path, "chunk": chunk_id, "chunks": chunks, "text": String::from_utf8_lossy(&data) }),
+                )
+            }
+            "extract_file" => {
+                let path = string("path")?;
+                let output_dir = arguments["output_dir"].as_str().unwrap_or(".");
+                // Models only get to write below the working directory
+                let escapes = Path::new(output_dir).components().any(|component| {
+                    !matches!(
+                        component,
+                        std::path::Component::Normal(_) | std::path::Component::CurDir
+                    )
+                });
+                if escapes {
+                    return Err(format!(
+                        "output_dir must be a relative path inside the working directory, got '{}'",
+                        output_dir
+                    )
+                    .into());
+                }
+                if self.extract_chunks(chain, output_dir, &|chunk| chunk.file_name == path)? == 0 {
+                    return Err(format!("{} is not in the archive", path).into());
+                }
+                Ok(json!({ "extracted": path, "output_dir": output_dir }))
+            }
+            "stats" => Ok(json!(metadata.stats())),
+            other => Err(format!("Unknown tool '{}'", other).into()),
+        }
+    }
+
+    /// Serves archives over the Model Context Protocol on stdin/stdout, as
+    /// newline-delimited JSON-RPC 2.0. Each archive is a resource, its files are
+    /// readable as `mnbl://ARCHIVE/PATH`, and the tools from `archive_tools` take
+    /// an extra `archive` argument. 2FA codes come only from `MOONBALL_OTP`,
+    /// never from a prompt, since stdin and stdout carry the protocol.
+    pub fn serve_mcp(&self, archive_paths: &[&str]) -> Result<(), MoonBallError> {
+        let otp = std::env::var(OTP_ENV_VAR)
+            .ok()
+            .map(|otp| otp.trim().to_string());
+        let mut archives: Vec<ServedArchive> = Vec::new();
+        for &path in archive_paths {
+            let chain = self.open_chain(path)?;
+            if chain[0].1.requires_2fa && otp.is_none() {
+                return Err(
+                    format!("{} requires 2FA; set {} to serve it", path, OTP_ENV_VAR).into(),
+                );
+            }
+            self.check_otp(&chain[0].1, || otp.clone())?;
+            let name = Path::new(path).file_name().map_or_else(
+                || path.to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            );
+            if archives.iter().any(|served| served.name == name) {
+                return Err(format!("Two served archives are named {}", name).into());
+            }
+            archives.push(ServedArchive { name, chain });
+        }
+        info!("Serving {} archives over MCP", archives.len());
+
+        let stdin = std::io::stdin();
+        let mut stdout = std::io::stdout().lock();
+        for line in stdin.lock().lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<serde_json::Value>(&line) {
+                Ok(request) => {
+                    let result = self.handle_mcp(
+                        &archives,
+                        request["method"].as_str().unwrap_or(""),
+                        &request["params"],
+                    );
+                    // Notifications carry no id and get no response
+                    let Some(id) = request.get("id") else {
+                        continue;
+                    };
+                    match result {
+                        Ok(result) => {
+                            serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result })
+                        }
+                        Err((code, message)) => {
+                            serde_json::json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+                        }
+                    }
+                }
+                Err(err) => {
+                    serde_json::json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32700, "message": err.to_string() } })
+                }
+            };
+            writeln!(stdout, "{}", response)?;
+            stdout.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles one MCP request; errors are JSON-RPC error codes with a message.
+    fn handle_mcp(
+        &self,
+        archives: &[ServedArchive],
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Result<serde_json::Value, (i64, String)> {
+        use serde_json::json;
+        let find = |name: Option<&str>| -> Result<&ServedArchive, (i64, String)> {
+            match name {
+                None => archives
+                    .first()
+                    .ok_or((-32602, "No archives are being served".to_string())),
+                Some(name) => archives
+                    .iter()
+                    .find(|served| served.name == name)
+                    .ok_or((-32602, format!("Unknown archive '{}'", name))),
+            }
+        };
+
+        match method {
+            "initialize" => Ok(json!({
+                "protocolVersion": MCP_PROTOCOL_VERSION,
+                "capabilities": { "tools": {}, "resources": {} },
+                "serverInfo": { "name": "moonball", "version": VERSION },
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => {
+                let names: Vec<&str> = archives.iter().map(|served| served.name.as_str()).collect();
+                let tools: Vec<serde_json::Value> = archive_tools()
+                    .into_iter()
+                    .map(|tool| {
+                        let mut schema = tool.parameters;
+                        schema["properties"]["archive"] = json!({
+                            "type": "string",
+                            "description": "Archive to use (default: the first one served)",
+                            "enum": names,
+                        });
+                        json!({ "name": tool.name, "description": tool.description, "inputSchema": schema })
+                    })
+                    .collect();
+                Ok(json!({ "tools": tools }))
+            }
+            "tools/call" => {
+                let name = params["name"]
+                    .as_str()
+                    .ok_or((-32602, "Missing tool name".to_string()))?;
+                let arguments = &params["arguments"];
+                let served = find(arguments["archive"].as_str())?;
+                // Tool failures are results the model should see, not protocol errors
+                let (text, is_error) = match self.call_tool(&served.chain, name, arguments) {
+                    Ok(output) => (truncate_output(output.to_string()), false),
+                    Err(err) => (err.to_string(), true),
+                };
+                Ok(json!({ "content": [{ "type": "text", "text": text }], "isError": is_error }))
+            }
+            "resources/list" => {
+                let resources: Vec<serde_json::Value> = archives
+                    .iter()
+                    .map(|served| {
+                        let stats = served.chain[0].1.stats();
+                        json!({
+                            "uri": format!("mnbl://{}", served.name),
+                            "name": served.name,
+                            "description": format!("MoonBall archive with {} files ({} bytes)", stats.files, stats.original_size),
+                            "mimeType": "application/json",
+                        })
+                    })
+                    .collect();
+                Ok(json!({ "resources": resources }))
+            }
+            "resources/templates/list" => Ok(json!({
+                "resourceTemplates": [{
+                    "uriTemplate": "mnbl://{archive}/{path}",
+                    "name": "Archived file",
+                    "description": "A file stored in a served archive, decompressed",
+                }],
+            })),
+            "resources/read" => {
+                let uri = params["uri"]
+                    .as_str()
+                    .ok_or((-32602, "Missing uri".to_string()))?;
+                let location = uri
+                    .strip_prefix("mnbl://")
+                    .ok_or((-32602, format!("Not a MoonBall resource: {}", uri)))?;
+                let (archive_name, file_name) = match location.split_once('/') {
+                    Some((archive_name, file_name)) => (archive_name, Some(file_name)),
+                    None => (location, None),
+                };
+                let served = find(Some(archive_name))?;
+                let metadata = &served.chain[0].1;
+
+                let content = match file_name {
+                    None => json!({
+                        "uri": uri,
+                        "mimeType": "application/json",
+                        "text": json!({ "stats": metadata.stats(), "files": metadata.file_summaries() }).to_string(),
+                    }),
+                    Some(file_name) => {
+                        if metadata.find_chunk(file_name, 0).is_none() {
+                            return Err((-32002, format!("Resource not found: {}", uri)));
+                        }
+                        let data = self
+                            .read_file(&served.chain, file_name)
+                            .map_err(|err| (-32603, err.to_string()))?;
+                        match String::from_utf8(data) {
+                            Ok(text) => {
+                                json!({ "uri": uri, "mimeType": "text/plain", "text": text })
+                            }
+                            Err(err) => {
+                                json!({ "uri": uri, "mimeType": "application/octet-stream", "blob": base64::encode(err.into_bytes()) })
+                            }
+                        }
+                    }
+                };
+                Ok(json!({ "contents": [content] }))
+            }
+            method if method.starts_with("notifications/") => Ok(serde_json::Value::Null),
+            other => Err((-32601, format!("Method not found: {}", other))),
+        }
+    }
+
+    /// Runs an interactive session over an archive. Plain lines are questions;
+    /// lines starting with `/` are commands (see `CHAT_HELP`).
+    pub fn chat(&self, archive_path: &str, top_k: usize) -> Result<(), MoonBallError> {
+        let client = ChatClient::from_config(&self.config)?;
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+
+        let mut history: Vec<ChatMessage> = Vec::new();
+        let mut grounding: Vec<Vec<Source>> = Vec::new(); // Sources of each answer, in order
+        println!(
+            "Chatting about {} with {} ({}). Type /help for commands.",
+            archive_path, client.model, client.provider
+        );
+
+        let stdin = std::io::stdin();
+        loop {
+            print!("> ");
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim();
+            let (command, argument) = line
+                .split_once(' ')
+                .map_or((line, ""), |(command, argument)| (command, argument.trim()));
+
+            let result = match command {
+                "" => Ok(()),
+                "/quit" | "/exit" => break,
+                "/help" => {
+                    println!("{}", CHAT_HELP);
+                    Ok(())
+                }
+                "/search" => self
+                    .search_chain(&chain, argument, top_k)
+                    .map(|hits| print_hits(&hits, std::io::stdout().is_terminal())),
+                "/cat" => {
+                    let (file_name, chunk_id) = parse_chunk_ref(argument);
+                    self.write_chunk(
+                        &chain,
+                        archive_path,
+                        file_name,
+                        chunk_id,
+                        &mut std::io::stdout().lock(),
+                    )
+                    .map(|_| println!())
+                }
+                "/sources" => {
+                    let number = if argument.is_empty() {
+                        Ok(grounding.len())
+                    } else {
+                        argument.parse::<usize>()
+                    };
+                    match number
+                        .ok()
+                        .and_then(|number| grounding.get(number.wrapping_sub(1)))
+                    {
+                        Some(sources) => print_sources(sources, true),
+                        None => println!("No answer {}; {} so far", argument, grounding.len()),
+                    }
+                    Ok(())
+                }
+                "/extract" => {
+                    let mut arguments = argument.split_whitespace();
+                    let target = arguments.next().unwrap_or("");
+                    let output_dir = arguments.next().unwrap_or(".");
+                    // A number refers to a source of the last answer
+                    let file_name = match target.parse::<usize>() {
+                        Ok(number) => grounding
+                            .last()
+                            .and_then(|sources| {
+                                sources.iter().find(|source| source.number == number)
+                            })
+                            .map(|source| source.file_name.as_str()),
+                        Err(_) if !target.is_empty() => Some(target),
+                        Err(_) => None,
+                    };
+                    match file_name {
+                        Some(file_name) => self
+                            .extract_chunks(&chain, output_dir, &|chunk| {
+                                chunk.file_name == file_name
+                            })
+                            .and_then(|count| {
+                                if count == 0 {
+                                    Err(format!("{} is not in {}", file_name, archive_path).into())
+                                } else {
+                                    Ok(())
+                                }
+                            })
+                            .map(|_| println!("Extracted {} to {}", file_name, output_dir)),
+                        None => {
+                            println!("Usage: /extract PATH|SOURCE [DIR]");
+                            Ok(())
+                        }
+                    }
+                }
+                _ if command.starts_with('/') => {
+                    println!("Unknown command {}; type /help", command);
+                    Ok(())
+                }
+                _ => self
+                    .answer(&client, archive_path, &chain, &history, line, top_k)
+                    .map(|answer| {
+                        println!("{}\n", answer.text);
+                        print_sources(&answer.sources, false);
+                        history.push(ChatMessage::new("user", line));
+                        history.push(ChatMessage::new("assistant", answer.text));
+                        grounding.push(answer.sources);
+                    }),
+            };
+            if let Err(err) = result {
+                println!("Error: {}", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Browses an archive in the terminal: a tree of entries, a preview of the
+    /// selected chunk, search, and extraction into `output_dir` on a keypress.
+    pub fn browse(
+        &self,
+        archive_path: &str,
+        output_dir: &str,
+        top_k: usize,
+    ) -> Result<(), MoonBallError> {
+        use crossterm::terminal::{
+            disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+        };
+
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+        let mut state = TuiState::new(archive_path, output_dir, chain);
+        state.load_preview(self);
+
+        enable_raw_mode()?;
+        crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::CrosstermBackend::new(std::io::stdout()))?;
+        let result = self.run_tui(&mut terminal, &mut state, top_k);
+        disable_raw_mode()?;
+        crossterm::execute!(std::io::stdout(), LeaveAlternateScreen)?;
+        terminal.show_cursor()?;
+        result
+    }
+
+    fn run_tui<B: ratatui::backend::Backend>(
+        &self,
+        terminal: &mut ratatui::Terminal<B>,
+        state: &mut TuiState,
+        top_k: usize,
+    ) -> Result<(), MoonBallError> {
+        use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+
+        loop {
+            terminal.draw(|frame| state.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+
+            if let Some(input) = state.input.as_mut() {
+                match key.code {
+                    KeyCode::Char(c) => input.push(c),
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Esc => state.input = None,
+                    KeyCode::Enter => {
+                        let query = state.input.take().unwrap_or_default();
+                        if query.trim().is_empty() {
+                            continue;
+                        }
+                        state.status = format!("Searching for \"{}\"...", query);
+                        terminal.draw(|frame| state.draw(frame))?;
+                        match self.search_chain(&state.chain, &query, top_k) {
+                            Ok(hits) => {
+                                state.status = format!(
+                                    "{} results for \"{}\", Esc returns to the tree",
+                                    hits.len(),
+                                    query
+                                );
+                                state.hits = hits;
+                                state.showing_hits = true;
+                                state.list.select(Some(0));
+                                state.load_preview(self);
+                            }
+                            Err(err) => state.status = format!("Search failed: {}", err),
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Up | KeyCode::Char('k') => state.move_selection(-1),
+                KeyCode::Down | KeyCode::Char('j') => state.move_selection(1),
+                KeyCode::Right | KeyCode::Enter | KeyCode::Char('l') => state.expand(true),
+                KeyCode::Left | KeyCode::Char('h') => state.expand(false),
+                KeyCode::Char('[') => state.step_chunk(-1),
+                KeyCode::Char(']') => state.step_chunk(1),
+                KeyCode::PageUp => {
+                    state.scroll = state.scroll.saturating_sub(10);
+                    continue;
+                }
+                KeyCode::PageDown => {
+                    state.scroll = state.scroll.saturating_add(10);
+                    continue;
+                }
+                KeyCode::Char('/') => {
+                    state.input = Some(String::new());
+                    continue;
+                }
+                KeyCode::Esc if state.showing_hits => {
+                    state.showing_hits = false;
+                    state.list.select(Some(0));
+                    state.status = TUI_HELP.to_string();
+                }
+                KeyCode::Char('x') => {
+                    state.extract(self, false);
+                    continue;
+                }
+                KeyCode::Char('X') => {
+                    state.extract(self, true);
+                    continue;
+                }
+                KeyCode::Char('?') => {
+                    state.status = TUI_HELP.to_string();
+                    continue;
+                }
+                _ => continue,
+            }
+            state.load_preview(self);
+        }
+    }
+
+    /// Formats hits as numbered excerpts within `budget` tokens. The last excerpt
+    /// that doesn't fit whole is cut short; binary chunks are skipped.
+    fn build_context(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        hits: &[SearchHit],
+        mut budget: usize,
+    ) -> Result<(String, Vec<Source>), MoonBallError> {
+        let metadata = &chain[0].1;
+        let mut context = String::new();
+        let mut sources = Vec::new();
+        let mut block_cache = None;
+
+        for hit in hits {
+            let chunk = metadata
+                .find_chunk(&hit.file_name, hit.chunk_id)
+                .ok_or("Search hit is missing from the index")?;
+            let data = self.load_chunk(chain, chunk, &mut block_cache)?;
+            if data.contains(&0) {
+                continue;
+            }
+
+            let number = sources.len() + 1;
+            let header = format!(
+                "[{}] {} (chunk {}, bytes {}..{})\n",
+                number, hit.file_name, hit.chunk_id, hit.byte_range.start, hit.byte_range.end
+            );
+            let header_tokens = estimate_tokens(&header);
+            if budget <= header_tokens + 16 {
+                break;
+            }
+            let mut text = String::from_utf8_lossy(&data).into_owned();
+            let room = (budget - header_tokens) * 4;
+            if text.len() > room {
+                let mut end = room;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+            }
+
+            budget = budget.saturating_sub(header_tokens + estimate_tokens(&text));
+            context.push_str(&header);
+            context.push_str(&text);
+            context.push_str("\n\n");
+            sources.push(Source {
+                number,
+                file_name: hit.file_name.clone(),
+                chunk_id: hit.chunk_id,
+                byte_range: hit.byte_range.clone(),
+                cited: false,
+            });
+        }
+
+        Ok((context, sources))
+    }
+
+    /// Ranks the chunks of an archive against `query`. The query is embedded once per
+    /// embedding space with that space's model; spaces whose model can no longer
+    /// produce compatible vectors are skipped rather than compared.
+    pub fn semantic_search(
+        &self,
+        archive_path: &str,
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<SearchHit>, MoonBallError> {
+        let _log = LogScope::archive(archive_path);
+        let metadata = self.read_index(archive_path)?;
+        self.verify_otp(&metadata)?;
+        self.rank_by_meaning(archive_path, &metadata, query, top_k)
+    }
+
+    fn rank_by_meaning(
+        &self,
+        archive_path: &str,
+        metadata: &ArchiveMetadata,
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<SearchHit>, MoonBallError> {
+        let blocks = self.load_embeddings(archive_path, metadata)?;
+        let graphs = self.load_ann(archive_path, metadata)?;
+        let ann = &self.config.semantic_search.ann;
+        let mut hits = Vec::new();
+        let mut searched_spaces = 0;
+
+        for space in &metadata.embedding_spaces {
+            let query_embedding = match self.generate_embedding(query.as_bytes(), &space.model) {
+                Ok(embedding) => embedding,
+                Err(err) => {
+                    log::warn!(
+                        "Skipping embeddings from {}: the query could not be embedded: {}",
+                        space.model,
+                        err
+                    );
+                    continue;
+                }
+            };
+            if !space.matches(&query_embedding) {
+                log::warn!(
+                    "Skipping embeddings from {} {} ({} dimensions): the installed model is {} ({} dimensions); run --reindex to refresh them",
+                    space.model,
+                    space.version,
+                    space.dimension,
+                    query_embedding.version,
+                    query_embedding.vector.len()
+                );
+                continue;
+            }
+            searched_spaces += 1;
+
+            for (block_index, (block, vectors)) in metadata
+                .embedding_blocks
+                .iter()
+                .zip(&blocks)
+                .enumerate()
+                .filter(|(_, (block, _))| block.space == space.id)
+            {
+                let query = &query_embedding.vector;
+                let scored: Vec<(usize, f32)> = match graphs.get(&block_index) {
+                    Some(graph) => {
+                        // The graph ranks by inner product; re-ranking widens the candidate
+                        // pool and orders it by cosine similarity instead. Both use the stored
+                        // vectors, so with quantized storage this recovers recall, not precision
+                        let pool = if ann.rerank {
+                            top_k * ann.rerank_factor
+                        } else {
+                            top_k
+                        };
+                        let similarity = |node: usize| dot_product(&vectors.decode(node), query);
+                        graph
+                            .search(&similarity, ann.ef_search.max(pool))
+                            .into_iter()
+                            .take(pool)
+                            .map(|candidate| {
+                                let score = if ann.rerank {
+                                    cosine_similarity(&vectors.decode(candidate.node), query)
+                                } else {
+                                    candidate.score
+                                };
+                                (candidate.node, score)
+                            })
+                            .collect()
+                    }
+                    None => vectors.scores(query).into_iter().enumerate().collect(),
+                };
+                for (index, score) in scored {
+                    if score >= self.config.semantic_search.threshold {
+                        let chunk = &metadata.chunks[block.chunks[index]];
+                        hits.push(SearchHit::new(metadata, chunk, score));
+                    }
+                }
+            }
+
+            // Archives written before the embeddings section keep their vectors inline
+            for chunk in metadata
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.embedding_space == Some(space.id))
+            {
+                if let Some(embedding) = &chunk.embedding {
+                    let score = cosine_similarity(&query_embedding.vector, embedding);
+                    if score >= self.config.semantic_search.threshold {
+                        hits.push(SearchHit::new(metadata, chunk, score));
+                    }
+                }
+            }
+        }
+
+        let unidentified = metadata
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.embedding.is_some() && chunk.embedding_space.is_none())
+            .count();
+        if unidentified > 0 {
+            log::warn!("{} chunks have embeddings from an unknown model and were not searched; run --reindex", unidentified);
+        }
+        let pending = metadata
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.embedding_pending)
+            .count();
+        if pending > 0 {
+            log::warn!("{} chunks were archived without embeddings and were not searched by meaning; run --reindex --missing", pending);
+        }
+        if metadata.embedding_spaces.is_empty() {
+            return Err(format!("{} was archived without embeddings", archive_path).into());
+        }
+        if searched_spaces == 0 {
+            return Err(format!(
+                "{} has no embeddings compatible with the installed models",
+                archive_path
+            )
+            .into());
+        }
+
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+        hits.truncate(top_k);
+        Ok(hits)
+    }
+
+    /// Picks the codec and the preprocessing filters for a chunk.
+    fn predict_compression_algo(&self, chunk: &[u8], file_path: &str) -> (String, Vec<Filter>) {
+        let filters = self.predict_filters(chunk, file_path);
+
+        // Placeholder ML logic: restricted to the algorithms the scheme allows
+        let algorithms = &self.settings.algorithms;
+        if algorithms.iter().any(|algo| algo == "zstd-long")
+            && chunk.len() >= self.config.compression_algorithms.zstd_long.min_chunk_size
+        {
+            return ("zstd-long".to_string(), filters);
+        }
+        let text_like = !chunk.is_empty()
+            && chunk.iter().filter(|byte| byte.is_ascii()).count() * 10 >= chunk.len() * 9;
+        if text_like && algorithms.iter().any(|algo| algo == "brotli") {
+            return ("brotli".to_string(), filters);
+        }
+        (
+            algorithms
+                .first()
+                .cloned()
+                .unwrap_or_else(|| "zstd".to_string()),
+            filters,
+        )
+    }
+
+    fn predict_filters(&self, chunk: &[u8], file_path: &str) -> Vec<Filter> {
+        let extension = Path::new(file_path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let executable = matches!(
+            extension.as_str(),
+            "exe" | "dll" | "sys" | "so" | "o" | "a" | "bin"
+        ) || chunk.starts_with(b"MZ")
+            || chunk.starts_with(b"\x7fELF");
+        if executable {
+            return vec![Filter::Bcj];
+        }
+
+        let line_feeds = chunk.iter().filter(|byte| **byte == b'\n').count();
+        if line_feeds > 0
+            && chunk
+                .windows(2)
+                .filter(|pair| pair[0] == b'\r' && pair[1] == b'\n')
+                .count()
+                == line_feeds
+        {
+            // Only reversible when every CR belongs to a CRLF pair
+            let carriage_returns = chunk.iter().filter(|byte| **byte == b'\r').count();
+            if carriage_returns == line_feeds {
+                return vec![Filter::Crlf];
+            }
+            return Vec::new();
+        }
+
+        // Numeric data: keep the delta distance that lowers entropy the most on a sample
+        let sample = &chunk[..chunk.len().min(64 * 1024)];
+        let baseline = byte_entropy(sample);
+        [1, 2, 4, 8]
+            .iter()
+            .map(|distance| {
+                (
+                    *distance,
+                    byte_entropy(
+                        &Filter::Delta {
+                            distance: *distance,
+                        }
+                        .apply(sample),
+                    ),
+                )
+            })
+            .filter(|(_, entropy)| *entropy < baseline * 0.9)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(distance, _)| vec![Filter::Delta { distance }])
+            .unwrap_or_default()
+    }
+
+    /// Writes the archive to `archive_path`. It is written next to it under a
+    /// temporary name and only renamed into place once complete, so a failed or
+    /// cancelled save leaves any existing file at `archive_path` untouched.
+    pub fn save_archive(&mut self, archive_path: &str) -> Result<(), MoonBallError> {
+        let _log = LogScope::archive(archive_path);
+        self.flush_solid_block()?;
+        if std::fs::metadata(archive_path).is_ok_and(|existing| existing.permissions().readonly()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} is read-only", archive_path),
+            )
+            .into());
+        }
+        let temp_path = format!("{}.{}.tmp", archive_path, std::process::id());
+        let temp_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        let result = self
+            .write_archive(temp_file)
+            .and_then(|()| Ok(std::fs::rename(&temp_path, archive_path)?));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn write_archive(&mut self, archive_file: File) -> Result<(), MoonBallError> {
+        let mut writer = BufWriter::new(archive_file);
+
+        let mut flags = if self.metadata.encryption_enabled {
+            FLAG_ENCRYPTED_INDEX
+        } else {
+            0
+        };
+        flags |= self.metadata.scheme.map_or(0, Scheme::header_bits);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&[flags])?;
+
+        // Chunks are written in index order so extraction can stream files back in sequence
+        let mut offset = 0u64;
+        let mut order = Vec::new();
+        let files = self.files.lock().unwrap();
+        let stored_chunks = self
+            .metadata
+            .chunks
+            .iter_mut()
+            .filter(|chunk| !chunk.base_ref && chunk.solid.is_none());
+        for chunk in stored_chunks.chain(self.metadata.solid_blocks.iter_mut()) {
+            let key = chunk_key(&chunk.file_name, chunk.chunk_id);
+            let data = files.get(&key).ok_or_else(|| {
+                format!(
+                    "No data for chunk {} of {}",
+                    chunk.chunk_id, chunk.file_name
+                )
+            })?;
+            chunk.offset = offset;
+            offset += data.len() as u64;
+            order.push((key, chunk.file_name.clone()));
+        }
+
+        self.begin_progress("Writing", offset, order.len());
+        for (key, file_name) in &order {
+            let data = &files[key];
+            writer.write_all(data)?;
+            self.advance(file_name, data.len() as u64)?;
+        }
+        drop(files);
+
+        let keywords = if self.config.semantic_search.keywords.enabled
+            && !self.keyword_index.documents.is_empty()
+        {
+            self.keyword_index.to_bytes()?
+        } else {
+            Vec::new()
+        };
+        let mut metadata = std::mem::take(&mut self.metadata);
+        let result = self.write_tail(&mut writer, &mut metadata, HEADER_LEN + offset, &keywords);
+        self.metadata = metadata;
+        result?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        self.finish_progress();
+
+        Ok(())
+    }
+
+    /// Writes the binary sections, the index and the footer, starting at file position `position`.
+    fn write_tail<W: Write>(
+        &self,
+        writer: &mut W,
+        metadata: &mut ArchiveMetadata,
+        position: u64,
+        keywords: &[u8],
+    ) -> Result<(), MoonBallError> {
+        let sections = self.build_sections(metadata, keywords)?;
+        self.write_sections(writer, metadata, &sections, position)
+    }
+
+    /// Builds the non-empty optional sections, moving embeddings out of the chunks.
+    fn build_sections(
+        &self,
+        metadata: &mut ArchiveMetadata,
+        keywords: &[u8],
+    ) -> Result<Vec<(&'static str, Vec<u8>)>, MoonBallError> {
+        let embeddings = metadata.pack_embeddings(&self.config.semantic_search.storage);
+        let ann = self.build_ann(metadata, &embeddings)?;
+        Ok([
+            ("embeddings", embeddings),
+            ("ann", ann),
+            ("keywords", keywords.to_vec()),
+        ]
+        .into_iter()
+        .filter(|(_, data)| !data.is_empty())
+        .collect())
+    }
+
+    /// Writes the sections followed by the index and footer, starting at file offset `position`.
+    fn write_sections<W: Write>(
+        &self,
+        writer: &mut W,
+        metadata: &mut ArchiveMetadata,
+        sections: &[(&str, Vec<u8>)],
+        mut position: u64,
+    ) -> Result<(), MoonBallError> {
+        metadata.sections.clear();
+        for (name, data) in sections {
+            position += self.write_section(writer, metadata, name, data, position)?;
+        }
+        self.write_index(writer, metadata, position)
+    }
+
+    /// Builds an HNSW graph for every embedding block large enough to benefit and
+    /// returns the encoded "ann" section. Graphs are built from the stored (possibly
+    /// quantized) vectors so they match what search reads back.
+    fn build_ann(
+        &self,
+        metadata: &mut ArchiveMetadata,
+        embeddings: &[u8],
+    ) -> Result<Vec<u8>, MoonBallError> {
+        let ann = &self.config.semantic_search.ann;
+        let mut section = Vec::new();
+        metadata.ann_indexes.clear();
+        if !ann.enabled {
+            return Ok(section);
+        }
+
+        for (block_index, block) in metadata
+            .embedding_blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.chunks.len() >= ann.min_vectors)
+        {
+            let dimension = metadata
+                .embedding_space(block.space)
+                .ok_or_else(|| format!("Embedding block refers to unknown space {}", block.space))?
+                .dimension;
+            let vectors = QuantizedVectors::from_block(
+                block,
+                dimension,
+                embeddings[block.offset as usize..(block.offset + block.length) as usize].to_vec(),
+            )?;
+            let decoded: Vec<Vec<f32>> = (0..vectors.count)
+                .map(|index| vectors.decode(index))
+                .collect();
+            let graph = Hnsw::build(&decoded, ann);
+            let data = graph.to_bytes();
+            info!(
+                "Built ANN index over {} vectors of embedding space {}",
+                decoded.len(),
+                block.space
+            );
+
+            metadata.ann_indexes.push(AnnIndex {
+                block: block_index,
+                m: graph.m,
+                entry_point: graph.entry_point,
+                max_level: graph.max_level,
+                offset: section.len() as u64,
+                length: data.len() as u64,
+            });
+            section.extend_from_slice(&data);
+        }
+
+        Ok(section)
+    }
+
+    /// Loads the HNSW graphs of an archive, keyed by embedding block.
+    fn load_ann(
+        &self,
+        archive_path: &str,
+        metadata: &ArchiveMetadata,
+    ) -> Result<HashMap<usize, Hnsw>, MoonBallError> {
+        let section = match self.read_section(archive_path, metadata, "ann")? {
+            Some(section) => section,
+            None => return Ok(HashMap::new()),
+        };
+        let mut graphs = HashMap::new();
+        for index in &metadata.ann_indexes {
+            let block = metadata
+                .embedding_blocks
+                .get(index.block)
+                .ok_or("ANN index refers to a missing embedding block")?;
+            let data = section
+                .get(index.offset as usize..(index.offset + index.length) as usize)
+                .ok_or("ANN index lies outside the ann section")?;
+            graphs.insert(
+                index.block,
+                Hnsw::from_bytes(index, block.chunks.len(), data)?,
+            );
+        }
+        Ok(graphs)
+    }
+
+    /// Writes one section (encrypted along with the index) and records it; returns its stored length.
+    fn write_section<W: Write>(
+        &self,
+        writer: &mut W,
+        metadata: &mut ArchiveMetadata,
+        name: &str,
+        data: &[u8],
+        offset: u64,
+    ) -> Result<u64, MoonBallError> {
+        let stored = if metadata.encryption_enabled {
+            self.encrypt_data(data)?
+        } else {
+            data.to_vec()
+        };
+        writer.write_all(&stored)?;
+        metadata.sections.push(SectionMetadata {
+            name: name.to_string(),
+            offset,
+            length: stored.len() as u64,
+            checksum: format!("{:x}", md5::compute(data)),
+        });
+        Ok(stored.len() as u64)
+    }
+
+    /// Reads and verifies a section, or returns `None` when the archive doesn't have it.
+    fn read_section(
+        &self,
+        archive_path: &str,
+        metadata: &ArchiveMetadata,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, MoonBallError> {
+        let section = match metadata.section(name) {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let mut file = File::open(archive_path)?;
+        file.seek(SeekFrom::Start(section.offset))?;
+        let mut data = vec![0; section.length as usize];
+        file.read_exact(&mut data)?;
+        if metadata.encryption_enabled {
+            data = self.decrypt_data(&data)?;
+        }
+        if format!("{:x}", md5::compute(&data)) != section.checksum {
+            return Err(MoonBallError::ChecksumMismatch {
+                archive: archive_path.to_string(),
+                part: format!("{} section", name),
+                chunk: None,
+            });
+        }
+        Ok(Some(data))
+    }
+
+    /// Loads the embedding blocks of an archive, in `embedding_blocks` order.
+    fn load_embeddings(
+        &self,
+        archive_path: &str,
+        metadata: &ArchiveMetadata,
+    ) -> Result<Vec<QuantizedVectors>, MoonBallError> {
+        let section = match self.read_section(archive_path, metadata, "embeddings")? {
+            Some(section) => section,
+            None => return Ok(Vec::new()),
+        };
+        metadata
+            .embedding_blocks
+            .iter()
+            .map(|block| -> Result<QuantizedVectors, MoonBallError> {
+                let space = metadata.embedding_space(block.space).ok_or_else(|| {
+                    format!("Embedding block refers to unknown space {}", block.space)
+                })?;
+                let data = section
+                    .get(block.offset as usize..(block.offset + block.length) as usize)
+                    .ok_or("Embedding block lies outside the embeddings section")?;
+                QuantizedVectors::from_block(block, space.dimension, data.to_vec())
+            })
+            .collect()
+    }
+
+    /// Puts the embeddings stored in the embeddings section back onto their chunks.
+    fn unpack_embeddings(
+        &self,
+        archive_path: &str,
+        metadata: &mut ArchiveMetadata,
+    ) -> Result<(), MoonBallError> {
+        let blocks = self.load_embeddings(archive_path, metadata)?;
+        for (block, vectors) in metadata.embedding_blocks.iter().zip(&blocks) {
+            for (index, &position) in block.chunks.iter().enumerate() {
+                if let Some(chunk) = metadata.chunks.get_mut(position) {
+                    chunk.embedding = Some(vectors.decode(index));
+                }
+            }
+        }
+        metadata.embedding_blocks.clear();
+        metadata.ann_indexes.clear();
+        Ok(())
+    }
+
+    /// Writes the index followed by the footer that points back at it.
+    fn write_index<W: Write>(
+        &self,
+        writer: &mut W,
+        metadata: &ArchiveMetadata,
+        index_offset: u64,
+    ) -> Result<(), MoonBallError> {
+        let metadata_json = serde_json::to_string(metadata)?;
+        let index = if metadata.encryption_enabled {
+            self.encrypt_data(metadata_json.as_bytes())?
+        } else {
+            metadata_json.as_bytes().to_vec()
+        };
+        writer.write_all(&index)?;
+
+        let checksum = format!("{:x}", md5::compute(&metadata_json));
+        writer.write_all(&index_offset.to_le_bytes())?;
+        writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        writer.write_all(checksum.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Replaces the sections and index of an existing archive; the chunk data in
+    /// front of them is left untouched. The new tail is first appended after the old
+    /// one, so an interruption leaves a readable archive, and only then moved down
+    /// over the old tail.
+    fn rewrite_tail(
+        &self,
+        archive_path: &str,
+        metadata: &mut ArchiveMetadata,
+        keywords: &[u8],
+    ) -> Result<(), MoonBallError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(archive_path)?;
+        let footer = read_footer(&mut file, archive_path)?;
+        // `metadata.sections` still describes the layout on disk at this point
+        let data_end = metadata
+            .sections
+            .iter()
+            .map(|section| section.offset)
+            .min()
+            .unwrap_or(footer.index_offset);
+        let old_end = file.seek(SeekFrom::End(0))?;
+        let sections = self.build_sections(metadata, keywords)?;
+
+        let mut appended = Vec::new();
+        self.write_sections(&mut appended, metadata, &sections, old_end)?;
+        file.write_all(&appended)?;
+        file.sync_all()?;
+
+        // The appended copy is what readers find until the file is truncated, so the
+        // moved copy may only go where it can't overwrite it. Otherwise the old tail
+        // is left behind as unused space.
+        let mut moved = Vec::new();
+        self.write_sections(&mut moved, metadata, &sections, data_end)?;
+        if data_end + moved.len() as u64 <= old_end {
+            file.seek(SeekFrom::Start(data_end))?;
+            file.write_all(&moved)?;
+            file.sync_all()?;
+            file.set_len(data_end + moved.len() as u64)?;
+            file.sync_all()?;
+        } else {
+            self.write_sections(&mut std::io::sink(), metadata, &sections, old_end)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads and verifies the index of a single archive without touching its chunk data.
+    fn read_index(&self, archive_path: &str) -> Result<ArchiveMetadata, MoonBallError> {
+        let mut file = File::open(archive_path)?;
+        let footer = read_footer(&mut file, archive_path)?;
+
+        file.seek(SeekFrom::Start(footer.index_offset))?;
+        let mut index = vec![0; footer.index_len as usize];
+        file.read_exact(&mut index)?;
+        if footer.flags & FLAG_ENCRYPTED_INDEX != 0 {
+            index = self.decrypt_data(&index)?;
+        }
+
+        if format!("{:x}", md5::compute(&index)) != footer.checksum {
+            return Err(MoonBallError::ChecksumMismatch {
+                archive: archive_path.to_string(),
+                part: "index".to_string(),
+                chunk: None,
+            });
+        }
+
+        let mut metadata: ArchiveMetadata = serde_json::from_slice(&index)?;
+        metadata.scheme = Scheme::from_header_flags(footer.flags).or(metadata.scheme);
+        Ok(metadata)
+    }
+
+    /// The compression scheme recorded in the archive header. Unlike the index,
+    /// the header is never encrypted, so this needs no password.
+    pub fn archive_scheme(&self, archive_path: &str) -> Result<Option<Scheme>, MoonBallError> {
+        let footer = read_footer(&mut File::open(archive_path)?, archive_path)?;
+        Ok(Scheme::from_header_flags(footer.flags))
+    }
+
+    /// Recomputes every embedding with the currently configured models and rewrites
+    /// only the index; compressed chunk data stays untouched. Finished embeddings are
+    /// saved next to the archive so an interrupted run resumes where it stopped.
+    /// With `missing_only`, only chunks archived without an embedding are embedded
+    /// and the existing embeddings are kept.
+    pub fn reindex(&self, archive_path: &str, missing_only: bool) -> Result<usize, MoonBallError> {
+        let _log = LogScope::archive(archive_path);
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+        let chunks = &chain[0].1.chunks;
+        let targets: Vec<usize> = (0..chunks.len())
+            .filter(|&index| !missing_only || chunks[index].embedding_pending)
+            .collect();
+        let checksum = read_footer(&mut File::open(archive_path)?, archive_path)?.checksum;
+        let models = self.config.embedding_models();
+        let progress_path = format!("{}.reindex", archive_path);
+
+        let progress = std::fs::read(&progress_path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<ReindexProgress>(&bytes).ok())
+            .filter(|progress| {
+                let current = progress.index_checksum == checksum && progress.models == models;
+                if !current {
+                    info!("Discarding reindex progress for another index or other models");
+                }
+                current
+            })
+            .unwrap_or_else(|| ReindexProgress {
+                index_checksum: checksum,
+                models,
+                embeddings: HashMap::new(),
+            });
+        if !progress.embeddings.is_empty() {
+            info!(
+                "Resuming reindex of {}: {} of {} chunks already done",
+                archive_path,
+                progress.embeddings.len(),
+                targets.len()
+            );
+        }
+
+        let pending: Vec<(usize, &ChunkMetadata)> = targets
+            .iter()
+            .filter(|&index| !progress.embeddings.contains_key(index))
+            .map(|&index| (index, &chunks[index]))
+            .collect();
+
+        // Categories are detected per file from the start of its first chunk, as when archiving
+        let pending_files: HashSet<&str> = pending
+            .iter()
+            .map(|(_, chunk)| chunk.file_name.as_str())
+            .collect();
+        let mut categories: HashMap<&str, FileCategory> = HashMap::new();
+        let mut block_cache = None;
+        for chunk in chunks
+            .iter()
+            .filter(|chunk| chunk.chunk_id == 0 && pending_files.contains(chunk.file_name.as_str()))
+        {
+            let data = self.load_chunk(&chain, chunk, &mut block_cache)?;
+            let category = FileCategory::detect(&chunk.file_name, &data[..data.len().min(4096)]);
+            categories.insert(chunk.file_name.as_str(), category);
+        }
+
+        let progress = Mutex::new(progress);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.config.thread_count())
+            .build()?;
+        let failures: Vec<String> = pool.install(|| {
+            pending
+                .par_iter()
+                .map_init(
+                    || None,
+                    |block_cache, (index, chunk)| -> Result<(), String> {
+                        // Worker threads start with an empty log context
+                        let _archive = LogScope::archive(archive_path);
+                        let _chunk = LogScope::chunk(&chunk.file_name, chunk.chunk_id);
+                        let data = self
+                            .load_chunk(&chain, chunk, block_cache)
+                            .map_err(|err| err.to_string())?;
+                        let category = categories
+                            .get(chunk.file_name.as_str())
+                            .copied()
+                            .unwrap_or_else(|| {
+                                FileCategory::detect(
+                                    &chunk.file_name,
+                                    &data[..data.len().min(4096)],
+                                )
+                            });
+                        let model = self.config.model_for(category, chunk.chunk_id);
+                        let embedding = self
+                            .generate_embedding(&data, &model)
+                            .map_err(|err| err.to_string())?;
+
+                        let mut progress = progress.lock().unwrap();
+                        progress.embeddings.insert(
+                            *index,
+                            ReindexedChunk {
+                                embedding: embedding.vector,
+                                model: embedding.model,
+                                version: embedding.version,
+                            },
+                        );
+                        if progress.embeddings.len() % 16 == 0 {
+                            progress.save(&progress_path);
+                        }
+                        Ok(())
+                    },
+                )
+                .filter_map(|result| result.err())
+                .collect()
+        });
+
+        let mut progress = progress.into_inner().unwrap();
+        progress.save(&progress_path);
+        if !failures.is_empty() {
+            return Err(format!(
+                "{} chunks could not be embedded (first error: {}); rerun --reindex to resume",
+                failures.len(),
+                failures[0]
+            )
+            .into());
+        }
+
+        let mut metadata = self.read_index(archive_path)?;
+        if missing_only {
+            self.unpack_embeddings(archive_path, &mut metadata)?;
+        } else {
+            metadata.embedding_spaces.clear();
+        }
+        for &index in &targets {
+            let reindexed = progress
+                .embeddings
+                .remove(&index)
+                .ok_or("Reindex progress is missing chunks")?;
+            let embedding = Embedding {
+                vector: reindexed.embedding,
+                model: reindexed.model,
+                version: reindexed.version,
+            };
+            let space = metadata.embedding_space_for(&embedding);
+            let chunk = &mut metadata.chunks[index];
+            chunk.embedding = Some(embedding.vector);
+            chunk.embedding_model = Some(embedding.model);
+            chunk.embedding_space = Some(space);
+            chunk.embedding_pending = false;
+        }
+
+        // The keyword index doesn't depend on embeddings and is carried over as is
+        let keywords = self
+            .read_section(archive_path, &metadata, "keywords")?
+            .unwrap_or_default();
+        self.rewrite_tail(archive_path, &mut metadata, &keywords)?;
+        std::fs::remove_file(&progress_path)?;
+        Ok(targets.len())
+    }
+
+    /// Opens an archive and every base archive it builds on, newest first.
+    fn open_chain(
+        &self,
+        archive_path: &str,
+    ) -> Result<Vec<(String, ArchiveMetadata)>, MoonBallError> {
+        let mut chain = Vec::new();
+        let mut next = Some(archive_path.to_string());
+
+        while let Some(path) = next {
+            if chain.len() >= MAX_SNAPSHOT_CHAIN
+                || chain
+                    .iter()
+                    .any(|(seen, _): &(String, ArchiveMetadata)| *seen == path)
+            {
+                return Err(format!(
+                    "Snapshot chain starting at {} is cyclic or too long",
+                    archive_path
+                )
+                .into());
+            }
+            let metadata = self.read_index(&path)?;
+            next = metadata
+                .base_archive
+                .as_ref()
+                .map(|base| resolve_base_path(&path, base));
+            chain.push((path, metadata));
+        }
+
+        Ok(chain)
+    }
+
+    /// Returns the uncompressed contents of a chunk, replaying the snapshot chain
+    /// and unpacking solid blocks as needed. The last solid block is kept in `block_cache`.
+    fn load_chunk(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        chunk: &ChunkMetadata,
+        block_cache: &mut Option<(String, Vec<u8>)>,
+    ) -> Result<Vec<u8>, MoonBallError> {
+        let solid = match &chunk.solid {
+            Some(solid) => solid,
+            None => return self.load_stored(chain, &chunk.hash),
+        };
+
+        let cached = matches!(block_cache, Some((hash, _)) if *hash == solid.block_hash);
+        if !cached {
+            let block = self.load_stored(chain, &solid.block_hash)?;
+            *block_cache = Some((solid.block_hash.clone(), block));
+        }
+
+        let (_, block) = block_cache.as_ref().unwrap();
+        let data = block
+            .get(solid.offset..solid.offset + chunk.original_size)
+            .ok_or_else(|| {
+                MoonBallError::Format(format!("Solid block for {} is truncated", chunk.file_name))
+            })?;
+        Ok(data.to_vec())
+    }
+
+    /// Finds the archive in the chain that stores the data for `hash` and decompresses it.
+    fn load_stored(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        hash: &str,
+    ) -> Result<Vec<u8>, MoonBallError> {
+        for (path, metadata) in chain {
+            let stored = metadata
+                .chunks
+                .iter()
+                .chain(metadata.solid_blocks.iter())
+                .find(|candidate| {
+                    !candidate.base_ref && candidate.solid.is_none() && candidate.hash == hash
+                });
+            let stored = match stored {
+                Some(stored) => stored,
+                None => continue,
+            };
+
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(HEADER_LEN + stored.offset))?;
+            let mut data = vec![0; stored.compressed_size];
+            file.read_exact(&mut data)?;
+
+            if metadata.encryption_enabled {
+                data = self.decrypt_data(&data)?;
+            }
+            let data = self.decompress_chunk(stored, &data, metadata)?;
+            if chunk_hash(&data) != hash {
+                return Err(MoonBallError::ChecksumMismatch {
+                    archive: path.clone(),
+                    part: "chunk data".to_string(),
+                    chunk: Some((stored.file_name.clone(), stored.chunk_id)),
+                });
+            }
+            return Ok(data);
+        }
+
+        Err(MoonBallError::Format(format!(
+            "Chunk {} is missing from the snapshot chain",
+            hash
+        )))
+    }
+
+    fn decompress_chunk(
+        &self,
+        stored: &ChunkMetadata,
+        data: &[u8],
+        metadata: &ArchiveMetadata,
+    ) -> Result<Vec<u8>, MoonBallError> {
+        let codec = self.codecs.for_chunk(stored)?;
+        let dictionary = match stored.dictionary_id {
+            Some(id) => Some(metadata.dictionary(id)?),
+            None => None,
+        };
+        let params = CodecParams {
+            level: 0,
+            window_log: 0,
+            dictionary: dictionary.as_deref(),
+            original_size: stored.original_size,
+        };
+        let decompressed = codec.decompress(data, &params)?;
+        Ok(invert_filters(decompressed, &stored.filters))
+    }
+
+    pub fn extract(&self, archive_path: &str, output_dir: &str) -> Result<(), MoonBallError> {
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+        self.extract_chunks(&chain, output_dir, &|_| true)?;
+        Ok(())
+    }
+
+    /// Extracts only the named files and returns how many were written.
+    pub fn extract_files(
+        &self,
+        archive_path: &str,
+        output_dir: &str,
+        file_names: &HashSet<String>,
+    ) -> Result<usize, MoonBallError> {
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+        self.extract_chunks(&chain, output_dir, &|chunk| {
+            file_names.contains(&chunk.file_name)
+        })
+    }
+
+    /// Files stored in an archive, in the order they were added.
+    pub fn list_files(&self, archive_path: &str) -> Result<Vec<FileSummary>, MoonBallError> {
+        Ok(self.read_index(archive_path)?.file_summaries())
+    }
+
+    /// Writes the chunks accepted by `keep` to their files under `output_dir` and
+    /// returns how many files were written.
+    fn extract_chunks(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        output_dir: &str,
+        keep: &dyn Fn(&ChunkMetadata) -> bool,
+    ) -> Result<usize, MoonBallError> {
+        let _log = LogScope::archive(&chain[0].0);
+        let chunks: Vec<&ChunkMetadata> = chain[0]
+            .1
+            .chunks
+            .iter()
+            .filter(|chunk| keep(chunk))
+            .collect();
+        let bytes_total = chunks.iter().map(|chunk| chunk.original_size as u64).sum();
+        self.begin_progress("Extracting", bytes_total, chunks.len());
+
+        let mut files: Vec<(&str, Vec<&ChunkMetadata>)> = Vec::new();
+        let mut positions: HashMap<&str, usize> = HashMap::new();
+        for chunk in chunks {
+            let position = *positions
+                .entry(chunk.file_name.as_str())
+                .or_insert_with(|| {
+                    files.push((chunk.file_name.as_str(), Vec::new()));
+                    files.len() - 1
+                });
+            files[position].1.push(chunk);
+        }
+
+        // Checked up front so a bad name doesn't leave a partial extraction behind
+        let output_paths = files
+            .iter()
+            .map(|(file_name, _)| Ok(Path::new(output_dir).join(safe_relative_path(file_name)?)))
+            .collect::<Result<Vec<PathBuf>, MoonBallError>>()?;
+
+        let (policy, retry) = (self.config.error_policy(), self.config.retry.clone());
+        let mut report = RunReport::new("Extracted");
+        let mut written: Vec<PathBuf> = Vec::new();
+        let mut created_dirs: Vec<PathBuf> = Vec::new();
+        let mut block_cache = None;
+        for ((file_name, file_chunks), output_path) in files.iter().zip(output_paths) {
+            let outcome = run_with_policy(policy, &retry, file_name, || {
+                self.extract_file(
+                    chain,
+                    &output_path,
+                    file_chunks,
+                    &mut block_cache,
+                    &mut created_dirs,
+                )
+            });
+            match outcome {
+                Ok(outcome) => {
+                    if report.record(file_name, outcome).is_some() {
+                        written.push(output_path);
+                    }
+                }
+                Err(err) => {
+                    // The run stopped, so remove everything it wrote
+                    for path in &written {
+                        let _ = std::fs::remove_file(path);
+                    }
+                    created_dirs.sort_by_key(|dir| Reverse(dir.components().count()));
+                    for dir in &created_dirs {
+                        let _ = std::fs::remove_dir(dir);
+                    }
+                    *self.report.lock().unwrap() = report;
+                    return Err(err);
+                }
+            }
+        }
+        *self.report.lock().unwrap() = report;
+        self.finish_progress();
+
+        Ok(written.len())
+    }
+
+    /// Writes one file from its chunks, removing it again if that fails.
+    /// Directories it has to create are added to `created_dirs`.
+    fn extract_file(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        output_path: &Path,
+        chunks: &[&ChunkMetadata],
+        block_cache: &mut Option<(String, Vec<u8>)>,
+        created_dirs: &mut Vec<PathBuf>,
+    ) -> Result<(), MoonBallError> {
+        if let Some(parent) = output_path.parent() {
+            created_dirs.extend(
+                parent
+                    .ancestors()
+                    .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                    .map(Path::to_path_buf),
+            );
+            std::fs::create_dir_all(parent)?;
+        }
+        let result = self.write_file(chain, output_path, chunks, block_cache);
+        if result.is_err() {
+            let _ = std::fs::remove_file(output_path);
+        }
+        result
+    }
+
+    fn write_file(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        output_path: &Path,
+        chunks: &[&ChunkMetadata],
+        block_cache: &mut Option<(String, Vec<u8>)>,
+    ) -> Result<(), MoonBallError> {
+        let mut output = BufWriter::new(File::create(output_path)?);
+        for chunk in chunks {
+            let _log = LogScope::chunk(&chunk.file_name, chunk.chunk_id);
+            let data = self.load_chunk(chain, chunk, block_cache)?;
+            output.write_all(&data)?;
+            self.advance(&chunk.file_name, data.len() as u64)?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    /// Writes one decompressed chunk to `writer`. Only that chunk's stored data (or
+    /// its solid block) is read, located through the index.
+    pub fn cat(
+        &self,
+        archive_path: &str,
+        file_name: &str,
+        chunk_id: usize,
+        writer: &mut dyn Write,
+    ) -> Result<(), MoonBallError> {
+        let chain = self.open_chain(archive_path)?;
+        self.verify_otp(&chain[0].1)?;
+        self.write_chunk(&chain, archive_path, file_name, chunk_id, writer)
+    }
+
+    /// Decompresses every chunk of one file, in order.
+    fn read_file(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        file_name: &str,
+    ) -> Result<Vec<u8>, MoonBallError> {
+        let mut data = Vec::new();
+        let mut block_cache = None;
+        for chunk in chain[0]
+            .1
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.file_name == file_name)
+        {
+            data.extend_from_slice(&self.load_chunk(chain, chunk, &mut block_cache)?);
+        }
+        Ok(data)
+    }
+
+    fn write_chunk(
+        &self,
+        chain: &[(String, ArchiveMetadata)],
+        archive_path: &str,
+        file_name: &str,
+        chunk_id: usize,
+        writer: &mut dyn Write,
+    ) -> Result<(), MoonBallError> {
+        let metadata = &chain[0].1;
+        let chunk = metadata.find_chunk(file_name, chunk_id).ok_or_else(|| {
+            let chunks = metadata
+                .chunks
+                .iter()
+                .filter(|chunk| chunk.file_name == file_name)
+                .count();
+            if chunks == 0 {
+                format!("{} is not in {}", file_name, archive_path)
+            } else {
+                format!(
+                    "{} has {} chunks, there is no chunk {}",
+                    file_name, chunks, chunk_id
+                )
+            }
+        })?;
+        writer.write_all(&self.load_chunk(chain, chunk, &mut None)?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Asks for and checks a one-time password when the archive requires 2FA.
+    fn verify_otp(&self, metadata: &ArchiveMetadata) -> Result<(), MoonBallError> {
+        self.check_otp(metadata, || self.ask_for_otp())
+    }
+
+    /// Compares the current code for a 2FA archive against the one `provide`
+    /// returns. `provide` is only called when the archive requires 2FA.
+    fn check_otp(
+        &self,
+        metadata: &ArchiveMetadata,
+        provide: impl FnOnce() -> Option<String>,
+    ) -> Result<(), MoonBallError> {
+        if metadata.requires_2fa {
+            let secret_key = metadata
+                .secret_key
+                .clone()
+                .ok_or_else(|| MoonBallError::Otp("2FA secret key missing".to_string()))?;
+            let current_time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let period = metadata.otp_period.unwrap_or(30);
+            let alphabet = RFC4648 { padding: false };
+            let expected_otp = match metadata.otp_algorithm.as_deref().unwrap_or("SHA256") {
+                "SHA1" => totp_custom::<Sha1>(&secret_key, current_time, period, 6, &alphabet),
+                "SHA512" => totp_custom::<Sha512>(&secret_key, current_time, period, 6, &alphabet),
+                _ => totp_custom::<Sha256>(&secret_key, current_time, period, 6, &alphabet),
+            };
+            if let Some(provided_otp) = provide() {
+                if provided_otp != expected_otp {
+                    return Err(MoonBallError::Otp("Invalid OTP".to_string()));
+                }
+            } else {
+                return Err(MoonBallError::Otp(
+                    "OTP required for extraction".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, MoonBallError> {
+        let salt = rand::thread_rng().gen::<[u8; 16]>();
         let kdf = Hmac::<Sha256>::new_from_slice(b"moonball").unwrap();
         let mut mac = kdf.clone();
         mac.update(&salt);
         let key = mac.finalize().into_bytes();
 
-        let iv = rand::thread_rng().gen::<[u8; 16]>();
-        let cipher = cbc_encryptor(KeySize::KeySize256, &key[..], &iv[..], crypto::blockmodes::PkcsPadding);
-        let mut final_result = Vec::<u8>::new();
-        let mut read_buffer = RefReadBuffer::new(data);
-        let mut buffer = [0; 4096];
-        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+        let iv = rand::thread_rng().gen::<[u8; 16]>();
+        let cipher = cbc_encryptor(
+            KeySize::KeySize256,
+            &key[..],
+            &iv[..],
+            crypto::blockmodes::PkcsPadding,
+        );
+        let mut final_result = Vec::<u8>::new();
+        let mut read_buffer = RefReadBuffer::new(data);
+        let mut buffer = [0; 4096];
+        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+
+        loop {
+            let result = cipher.encrypt(&mut read_buffer, &mut write_buffer, true)?;
+            final_result.extend(write_buffer.take_read_buffer().take_remaining());
+            match result {
+                BufferResult::BufferUnderflow => break,
+                BufferResult::BufferOverflow => {}
+            }
+        }
+
+        Ok([salt.to_vec(), iv.to_vec(), final_result].concat())
+    }
+
+    fn decrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, MoonBallError> {
+        let salt = &data[..16];
+        let iv = &data[16..32];
+        let kdf = Hmac::<Sha256>::new_from_slice(b"moonball").unwrap();
+        let mut mac = kdf.clone();
+        mac.update(salt);
+        let key = mac.finalize().into_bytes();
+
+        let cipher = cbc_decryptor(
+            KeySize::KeySize256,
+            &key[..],
+            iv,
+            crypto::blockmodes::PkcsPadding,
+        );
+        let mut final_result = Vec::<u8>::new();
+        let mut read_buffer = RefReadBuffer::new(&data[32..]);
+        let mut buffer = [0; 4096];
+        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+
+        loop {
+            let result = cipher
+                .decrypt(&mut read_buffer, &mut write_buffer, true)
+                .map_err(|_| MoonBallError::WrongPassword)?;
+            final_result.extend(write_buffer.take_read_buffer().take_remaining());
+            match result {
+                BufferResult::BufferUnderflow => break,
+                BufferResult::BufferOverflow => {}
+            }
+        }
+
+        Ok(final_result)
+    }
+
+    fn ask_for_otp(&self) -> Option<String> {
+        if let Some(otp) = &self.otp {
+            return Some(otp.clone());
+        }
+        // Non-interactive callers such as `serve --mcp` pass the code in the environment
+        if let Ok(otp) = std::env::var(OTP_ENV_VAR) {
+            return Some(otp.trim().to_string());
+        }
+        println!("Enter 2FA OTP: ");
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input).ok()?;
+        Some(input.trim().to_string())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl ChatMessage {
+    fn new(role: &str, content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.into(),
+        }
+    }
+}
+
+/// Client for the chat endpoint of the configured provider: Ollama's `/api/chat`
+/// or an OpenAI-compatible `/chat/completions`.
+pub struct ChatClient {
+    provider: String,
+    api_base: String,
+    api_key: String,
+    model: String,
+    context_length: usize,
+    system_message: String,
+    http: reqwest::blocking::Client,
+}
+
+impl ChatClient {
+    fn from_config(config: &Config) -> Result<Self, MoonBallError> {
+        let settings = &config.fallback_provider_settings;
+        let (api_base, api_key, model, context_length, system_message) =
+            match config.chat_provider.as_str() {
+                "ollama" => (
+                    &settings.ollama.api_base,
+                    "",
+                    &settings.ollama.model,
+                    settings.ollama.context_length,
+                    &settings.ollama.system_message,
+                ),
+                "openai" => (
+                    &settings.openai.api_base,
+                    settings.openai.api_key.as_str(),
+                    &settings.openai.model,
+                    settings.openai.context_length,
+                    &settings.openai.system_message,
+                ),
+                other => return Err(format!("Unknown chat provider '{}'", other).into()),
+            };
+
+        let mut client = ChatClient {
+            provider: config.chat_provider.clone(),
+            api_base: api_base.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            model: model.clone(),
+            context_length: context_length as usize,
+            system_message: system_message.clone(),
+            http: reqwest::blocking::Client::builder()
+                .timeout(std::time::Duration::from_secs(300))
+                .build()?,
+        };
+        if client.model.eq_ignore_ascii_case("AUTODETECT") {
+            client.model = client.detect_model()?;
+            info!("Using {} model {}", client.provider, client.model);
+        }
+        Ok(client)
+    }
+
+    /// Picks the model the server is running (Ollama) or the first one it serves.
+    fn detect_model(&self) -> Result<String, MoonBallError> {
+        let model = if self.provider == "ollama" {
+            let running: serde_json::Value = self
+                .http
+                .get(format!("{}/api/ps", self.api_base))
+                .send()?
+                .error_for_status()?
+                .json()?;
+            let installed = || -> Result<serde_json::Value, MoonBallError> {
+                Ok(self
+                    .http
+                    .get(format!("{}/api/tags", self.api_base))
+                    .send()?
+                    .error_for_status()?
+                    .json()?)
+            };
+            match running["models"][0]["name"].as_str() {
+                Some(name) => Some(name.to_string()),
+                None => installed()?["models"][0]["name"].as_str().map(String::from),
+            }
+        } else {
+            let models: serde_json::Value = self
+                .authorized(self.http.get(format!("{}/models", self.api_base)))
+                .send()?
+                .error_for_status()?
+                .json()?;
+            models["data"][0]["id"].as_str().map(String::from)
+        };
+        model.ok_or_else(|| {
+            format!(
+                "{} at {} reports no models; set a model in fallback_provider_settings",
+                self.provider, self.api_base
+            )
+            .into()
+        })
+    }
+
+    fn authorized(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> reqwest::blocking::RequestBuilder {
+        if self.api_key.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.api_key)
+        }
+    }
+
+    /// Sends the conversation, prefixed with the configured system message, and returns the reply.
+    pub fn chat(&self, messages: &[ChatMessage]) -> Result<String, MoonBallError> {
+        let mut conversation = vec![ChatMessage::new("system", self.system_message.as_str())];
+        conversation.extend_from_slice(messages);
+
+        let reply = if self.provider == "ollama" {
+            let body = serde_json::json!({
+                "model": self.model,
+                "messages": conversation,
+                "stream": false,
+                "options": { "num_ctx": self.context_length },
+            });
+            let response: serde_json::Value = self
+                .http
+                .post(format!("{}/api/chat", self.api_base))
+                .json(&body)
+                .send()?
+                .error_for_status()?
+                .json()?;
+            response["message"]["content"].as_str().map(String::from)
+        } else {
+            let body = serde_json::json!({ "model": self.model, "messages": conversation });
+            let request = self.authorized(
+                self.http
+                    .post(format!("{}/chat/completions", self.api_base)),
+            );
+            let response: serde_json::Value =
+                request.json(&body).send()?.error_for_status()?.json()?;
+            response["choices"][0]["message"]["content"]
+                .as_str()
+                .map(String::from)
+        };
+        reply.ok_or_else(|| {
+            format!("{} returned a response without a message", self.provider).into()
+        })
+    }
+
+    /// Tokens left for context once the system message and a reply are accounted for.
+    fn prompt_budget(&self) -> usize {
+        let reserved_for_reply = (self.context_length / 4).min(2048);
+        self.context_length
+            .saturating_sub(reserved_for_reply + estimate_tokens(&self.system_message))
+    }
+
+    /// One round of OpenAI-style function calling. Returns the assistant message,
+    /// which either answers or requests tool calls. Ollama is reached through its
+    /// OpenAI-compatible `/v1` endpoint.
+    fn complete_with_tools(
+        &self,
+        messages: &[serde_json::Value],
+        tools: &[serde_json::Value],
+    ) -> Result<serde_json::Value, MoonBallError> {
+        let url = if self.provider == "ollama" {
+            format!("{}/v1/chat/completions", self.api_base)
+        } else {
+            format!("{}/chat/completions", self.api_base)
+        };
+        let body = serde_json::json!({ "model": self.model, "messages": messages, "tools": tools });
+        let response: serde_json::Value = self
+            .authorized(self.http.post(url))
+            .json(&body)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        match &response["choices"][0]["message"] {
+            serde_json::Value::Null => {
+                Err(format!("{} returned a response without a message", self.provider).into())
+            }
+            message => Ok(message.clone()),
+        }
+    }
+}
+
+const MAX_TOOL_ROUNDS: usize = 16;
+const MCP_PROTOCOL_VERSION: &str = "2024-11-05";
+const TOOL_OUTPUT_LIMIT: usize = 16 * 1024; // Bytes of a tool result handed back to the model
+
+/// An archive operation offered to language models, described by a JSON schema.
+pub struct ToolDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub parameters: serde_json::Value,
+}
+
+impl ToolDefinition {
+    fn to_openai(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "function",
+            "function": { "name": self.name, "description": self.description, "parameters": self.parameters },
+        })
+    }
+}
+
+pub fn archive_tools() -> Vec<ToolDefinition> {
+    use serde_json::json;
+    vec![
+        ToolDefinition {
+            name: "list_files",
+            description: "List the files stored in the archive with their sizes and chunk counts.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "prefix": { "type": "string", "description": "Only list paths starting with this prefix" },
+                    "limit": { "type": "integer", "description": "Maximum number of files to return (default 200)" },
+                },
+            }),
+        },
+        ToolDefinition {
+            name: "search",
+            description: "Search the archive by meaning and keywords. Returns matching chunks with snippets.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string" },
+                    "top_k": { "type": "integer", "description": "Number of results (default 10)" },
+                },
+                "required": ["query"],
+            }),
+        },
+        ToolDefinition {
+            name: "read_chunk",
+            description: "Read one decompressed chunk of a file. Large files are split into numbered chunks starting at 0.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string" },
+                    "chunk": { "type": "integer", "description": "Chunk number (default 0)" },
+                },
+                "required": ["path"],
+            }),
+        },
+        ToolDefinition {
+            name: "extract_file",
+            description: "Extract one file from the archive to disk.",
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string" },
+                    "output_dir": { "type": "string", "description": "Relative directory to extract into (default: current directory)" },
+                },
+                "required": ["path"],
+            }),
+        },
+        ToolDefinition {
+            name: "stats",
+            description: "Summarize the archive: file and chunk counts, sizes, compression ratio, scheme, encryption and embedding models.",
+            parameters: json!({ "type": "object", "properties": {} }),
+        },
+    ]
+}
+
+const CHAT_HELP: &str = "Type a question to ask about the archive, or a command:
+  /search QUERY          search the archive
+  /cat PATH[:CHUNK]      print one decompressed chunk
+  /extract PATH|N [DIR]  extract a file, or the file of source [N] of the last answer
+  /sources [N]           show the chunks that grounded answer N (default: the last)
+  /help                  show this help
+  /quit                  end the session";
+
+/// The newest messages that fit in `budget` tokens, starting with a user turn.
+fn trim_history(history: &[ChatMessage], budget: usize) -> &[ChatMessage] {
+    let mut used = 0;
+    let mut start = history.len();
+    while start > 0 {
+        used += estimate_tokens(&history[start - 1].content);
+        if used > budget {
+            break;
+        }
+        start -= 1;
+    }
+    while history
+        .get(start)
+        .is_some_and(|message| message.role != "user")
+    {
+        start += 1;
+    }
+    &history[start..]
+}
+
+fn print_hits(hits: &[SearchHit], ansi: bool) {
+    for hit in hits {
+        println!(
+            "{:.3}  {}:{}  bytes {}..{}",
+            hit.score, hit.file_name, hit.chunk_id, hit.byte_range.start, hit.byte_range.end
+        );
+        if let Some(snippet) = &hit.snippet {
+            println!("       {}", snippet.render(ansi));
+        }
+    }
+}
+
+/// Lists the sources an answer cited, or every source it was given when it cited
+/// none or `all` is set.
+fn print_sources(sources: &[Source], all: bool) {
+    let cited_any = sources.iter().any(|source| source.cited);
+    println!("Sources:");
+    for source in sources
+        .iter()
+        .filter(|source| all || source.cited || !cited_any)
+    {
+        let marker = if source.cited { "*" } else { " " };
+        println!(
+            " {}[{}] {}:{}  bytes {}..{}",
+            marker,
+            source.number,
+            source.file_name,
+            source.chunk_id,
+            source.byte_range.start,
+            source.byte_range.end
+        );
+    }
+}
+
+/// Splits `PATH[:CHUNK]`; without a numeric suffix the whole value is the path.
+fn parse_chunk_ref(entry: &str) -> (&str, usize) {
+    match entry
+        .rsplit_once(':')
+        .map(|(path, chunk)| (path, chunk.parse::<usize>()))
+    {
+        Some((path, Ok(chunk_id))) => (path, chunk_id),
+        _ => (entry, 0),
+    }
+}
+
+/// Cuts a tool result down to `TOOL_OUTPUT_LIMIT` bytes, saying so at the end.
+fn truncate_output(mut output: String) -> String {
+    if output.len() > TOOL_OUTPUT_LIMIT {
+        let mut end = TOOL_OUTPUT_LIMIT;
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str(" [truncated]");
+    }
+    output
+}
+
+/// Flags the sources an answer cites as `[n]`.
+fn mark_cited(mut sources: Vec<Source>, answer: &str) -> Vec<Source> {
+    for source in &mut sources {
+        source.cited = answer.contains(&format!("[{}]", source.number));
+    }
+    sources
+}
+
+/// Rough token count for budgeting prompts, at about four bytes per token.
+fn estimate_tokens(text: &str) -> usize {
+    text.len() / 4 + 1
+}
+
+/// A chunk handed to the model as context, numbered for citations.
+#[derive(Serialize, Debug)]
+pub struct Source {
+    pub number: usize,
+    pub file_name: String,
+    pub chunk_id: usize,
+    pub byte_range: std::ops::Range<u64>,
+    pub cited: bool, // The answer refers to this source as [number]
+}
+
+#[derive(Serialize, Debug)]
+pub struct Answer {
+    pub text: String,
+    pub sources: Vec<Source>,
+}
+
+/// Location of the index, read from the end of an archive.
+struct ArchiveFooter {
+    flags: u8,
+    index_offset: u64,
+    index_len: u64,
+    checksum: String,
+}
+
+fn read_footer(file: &mut File, archive_path: &str) -> Result<ArchiveFooter, MoonBallError> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(MoonBallError::Format(format!(
+            "{} is not a MoonBall archive",
+            archive_path
+        )));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version > FORMAT_VERSION {
+        return Err(MoonBallError::UnsupportedVersion {
+            archive: archive_path.to_string(),
+            version,
+        });
+    }
+
+    file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+    let mut footer = [0u8; FOOTER_LEN as usize];
+    file.read_exact(&mut footer)?;
+
+    Ok(ArchiveFooter {
+        flags: header[6],
+        index_offset: u64::from_le_bytes(footer[0..8].try_into()?),
+        index_len: u64::from_le_bytes(footer[8..16].try_into()?),
+        checksum: String::from_utf8_lossy(&footer[16..]).to_string(),
+    })
+}
+
+/// Embeddings finished by an interrupted `--reindex`, keyed by chunk position.
+#[derive(Serialize, Deserialize)]
+struct ReindexProgress {
+    index_checksum: String, // Progress only applies to the index it was computed for
+    #[serde(default)]
+    models: Vec<String>, // ...and to the models it was computed with
+    embeddings: HashMap<usize, ReindexedChunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReindexedChunk {
+    embedding: Vec<f32>,
+    model: String,
+    version: String,
+}
+
+impl ReindexProgress {
+    fn save(&self, path: &str) {
+        let result = serde_json::to_vec(self)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| std::fs::write(path, bytes).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            log::warn!("Could not save reindex progress to {}: {}", path, err);
+        }
+    }
+}
+
+fn chunk_key(file_path: &str, chunk_id: usize) -> String {
+    format!("{}_{}.mbc", file_path, chunk_id)
+}
+
+/// Recursively lists the files under `path` in a stable order.
+fn collect_files(path: &Path, files: &mut Vec<String>) -> Result<(), MoonBallError> {
+    if !path.is_dir() {
+        files.push(path.to_string_lossy().into_owned());
+        return Ok(());
+    }
+
+    let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        collect_files(&entry.path(), files)?;
+    }
+
+    Ok(())
+}
+
+fn gear_table() -> &'static [u64; 256] {
+    static GEAR: OnceLock<[u64; 256]> = OnceLock::new();
+    GEAR.get_or_init(|| {
+        // splitmix64 keeps the table deterministic so chunk boundaries are stable across runs
+        let mut table = [0u64; 256];
+        for (index, entry) in table.iter_mut().enumerate() {
+            *entry = splitmix64(0x9E37_79B9_7F4A_7C15u64.wrapping_mul(index as u64 + 2));
+        }
+        table
+    })
+}
+
+/// Mixes a counter into a well-distributed 64-bit value.
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn l2_normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|value| *value /= norm);
+    }
+    vector
+}
+
+fn dot_product(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+fn chunk_hash(chunk: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(chunk);
+    hasher.result_str()
+}
+
+/// The path a stored file name is extracted to, relative to the output directory.
+/// Names are recorded as given when archiving, so absolute paths lose their root
+/// and drive prefix; names that climb out with `..` are refused.
+fn safe_relative_path(file_name: &str) -> Result<PathBuf, MoonBallError> {
+    let mut path = PathBuf::new();
+    for component in Path::new(file_name).components() {
+        match component {
+            std::path::Component::Normal(part) => path.push(part),
+            std::path::Component::ParentDir => {
+                return Err(MoonBallError::Format(format!(
+                    "Refusing to extract {}: it points outside the output directory",
+                    file_name
+                )))
+            }
+            std::path::Component::RootDir
+            | std::path::Component::Prefix(_)
+            | std::path::Component::CurDir => {}
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err(MoonBallError::Format(format!(
+            "Refusing to extract {:?}: it names no file",
+            file_name
+        )));
+    }
+    Ok(path)
+}
+
+/// Base archives are recorded as given on the command line; relative paths that
+/// don't resolve from the working directory are tried next to the snapshot itself.
+fn resolve_base_path(snapshot_path: &str, base: &str) -> String {
+    let base_path = Path::new(base);
+    if base_path.is_absolute() || base_path.exists() {
+        return base.to_string();
+    }
+    match Path::new(snapshot_path).parent() {
+        Some(dir) => dir.join(base_path).to_string_lossy().into_owned(),
+        None => base.to_string(),
+    }
+}
+
+/// Where configuration comes from, lowest precedence first: built-in defaults, the
+/// user config, the project `config.yml`, `--config`, `MOONBALL_*` environment
+/// variables and finally CLI flags.
+#[derive(Default, Clone)]
+pub struct ConfigSources {
+    pub config_path: Option<String>,
+    pub overrides: Vec<(String, String)>, // Dotted field path and value, from CLI flags
+}
+
+struct ConfigReport {
+    config: Option<Config>,
+    layers: Vec<String>,
+    warnings: Vec<String>,
+    errors: Vec<String>,
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("moonball").join("config.yml"))
+}
+
+fn load_config(sources: &ConfigSources) -> Result<Config, MoonBallError> {
+    let report = load_config_report(sources);
+    for warning in &report.warnings {
+        log::warn!("{}", warning);
+    }
+    match report.config {
+        Some(config) if report.errors.is_empty() => Ok(config),
+        _ => Err(MoonBallError::Config(report.errors.join("\n  "))),
+    }
+}
+
+fn load_config_report(sources: &ConfigSources) -> ConfigReport {
+    let mut files: Vec<(PathBuf, bool)> = Vec::new();
+    if let Some(path) = user_config_path() {
+        files.push((path, false));
+    }
+    files.push((PathBuf::from("config.yml"), false));
+    if let Some(path) = &sources.config_path {
+        files.push((PathBuf::from(path), true));
+    }
+
+    let mut env_vars: Vec<(String, String)> = std::env::vars()
+        .filter(|(key, _)| key.starts_with("MOONBALL_") && key != OTP_ENV_VAR)
+        .collect();
+    env_vars.sort();
+    layer_config(files, env_vars, &sources.overrides)
+}
+
+/// Merges config `files` (path and whether it must exist), `MOONBALL_*` variables
+/// and CLI overrides over the defaults, in that order.
+fn layer_config(
+    files: Vec<(PathBuf, bool)>,
+    env_vars: Vec<(String, String)>,
+    overrides: &[(String, String)],
+) -> ConfigReport {
+    let defaults =
+        serde_yaml::to_value(Config::default()).expect("default configuration serializes");
+    let mut merged = defaults.clone();
+    let mut layers = vec!["built-in defaults".to_string()];
+    let mut warnings = Vec::new();
+    let mut errors = Vec::new();
+
+    for (path, required) in files {
+        let source = path.display().to_string();
+        if !path.exists() {
+            if required {
+                errors.push(format!("{}: file not found", source));
+            }
+            continue;
+        }
+        let layer = std::fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|text| serde_yaml::from_str::<Value>(&text).map_err(|err| err.to_string()));
+        match layer {
+            Ok(Value::Null) => layers.push(source),
+            Ok(layer) => {
+                unknown_keys(&defaults, &layer, "", &source, &mut warnings);
+                merge_values(&mut merged, layer);
+                layers.push(source);
+            }
+            Err(err) => errors.push(format!("{}: {}", source, err)),
+        }
+    }
+
+    for (key, value) in env_vars {
+        // MOONBALL_COMPRESSION_LEVEL=5, MOONBALL_TWO_FACTOR_AUTHENTICATION__ENABLED=true
+        let path: Vec<&str> = key["MOONBALL_".len()..].split("__").collect();
+        if set_value(&mut merged, &path, &value) {
+            layers.push(format!("environment {}", key));
+        } else {
+            warnings.push(format!("environment {}: no such setting", key));
+        }
+    }
+
+    for (path, value) in overrides {
+        let path: Vec<&str> = path.split('.').collect();
+        if set_value(&mut merged, &path, value) {
+            layers.push(format!("command line {}", path.join(".")));
+        }
+    }
+
+    let config = match serde_path_to_error::deserialize::<_, Config>(merged) {
+        Ok(config) => {
+            errors.extend(config.validate());
+            Some(config)
+        }
+        Err(err) => {
+            errors.push(format!("{}: {}", err.path(), err.inner()));
+            None
+        }
+    };
+
+    ConfigReport {
+        config,
+        layers,
+        warnings,
+        errors,
+    }
+}
+
+fn merge_values(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Mapping(base), Value::Mapping(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+/// Sets a setting from a string, matching keys case-insensitively. String settings
+/// keep the raw text; everything else is parsed as YAML so numbers and booleans work.
+fn set_value(root: &mut Value, path: &[&str], raw: &str) -> bool {
+    let (last, parents) = match path.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+
+    let mut current = root;
+    for segment in parents {
+        current = match find_key(current, segment) {
+            Some(next) => next,
+            None => return false,
+        };
+    }
+
+    match find_key(current, last) {
+        Some(target @ Value::String(_)) => *target = Value::String(raw.to_string()),
+        Some(target) => {
+            *target = serde_yaml::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()))
+        }
+        None => return false,
+    }
+    true
+}
+
+fn find_key<'a>(value: &'a mut Value, name: &str) -> Option<&'a mut Value> {
+    value
+        .as_mapping_mut()?
+        .iter_mut()
+        .find(|(key, _)| {
+            key.as_str()
+                .is_some_and(|key| key.eq_ignore_ascii_case(name))
+        })
+        .map(|(_, value)| value)
+}
+
+/// Collects keys a config layer sets that the defaults don't know about, which are
+/// almost always typos. Model maps accept any key and are checked by `Config::validate`.
+fn unknown_keys(
+    defaults: &Value,
+    layer: &Value,
+    path: &str,
+    source: &str,
+    warnings: &mut Vec<String>,
+) {
+    let (Some(defaults), Some(layer)) = (defaults.as_mapping(), layer.as_mapping()) else {
+        return;
+    };
+    if path.ends_with("custom_models") || path == "preset_configs" {
+        return;
+    }
+
+    for (key, value) in layer {
+        let name = key.as_str().unwrap_or_default();
+        let field_path = if path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", path, name)
+        };
+        match defaults.get(key) {
+            Some(default) => unknown_keys(default, value, &field_path, source, warnings),
+            None => warnings.push(format!("{}: unknown setting '{}'", source, field_path)),
+        }
+    }
+}
+
+/// Accepts `parallel_threads: 8` as well as `parallel_threads: 'auto'`.
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::String(value) => Ok(value),
+        Value::Number(value) => Ok(value.to_string()),
+        other => Err(serde::de::Error::custom(format!(
+            "expected a string or number, got {:?}",
+            other
+        ))),
+    }
+}
 
-        loop {
-            let result = cipher.encrypt(&mut read_buffer, &mut write_buffer, true)?;
-            final_result.extend(write_buffer.take_read_buffer().take_remaining());
-            match result {
-                BufferResult::BufferUnderflow => break,
-                BufferResult::BufferOverflow => {}
+/// Draws CLI progress on stderr.
+struct ProgressBarObserver {
+    bar: indicatif::ProgressBar,
+}
+
+impl ProgressBarObserver {
+    fn new() -> Self {
+        let bar = indicatif::ProgressBar::new(0);
+        let style = indicatif::ProgressStyle::with_template(
+            "{prefix:>11} [{bar:30}] {bytes}/{total_bytes} {msg}",
+        )
+        .expect("valid progress template")
+        .progress_chars("=> ");
+        bar.set_style(style);
+        ProgressBarObserver { bar }
+    }
+}
+
+impl ProgressObserver for ProgressBarObserver {
+    fn on_progress(&self, progress: &Progress) {
+        if self.bar.is_finished() {
+            self.bar.reset();
+        }
+        self.bar.set_prefix(progress.operation.clone());
+        self.bar.set_length(progress.bytes_total);
+        self.bar.set_position(progress.bytes_done);
+        let eta = progress.eta().map_or(String::new(), |eta| {
+            format!("ETA {}:{:02} ", eta.as_secs() / 60, eta.as_secs() % 60)
+        });
+        self.bar
+            .set_message(format!("{}{}", eta, progress.current_file));
+    }
+
+    fn on_finish(&self, _progress: &Progress) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Shows a progress bar when stderr is a terminal, and makes Ctrl-C stop the
+/// operation at the next chunk. A second Ctrl-C exits immediately.
+fn attach_cli_progress(archive: &mut MoonBallArchive) -> Result<(), MoonBallError> {
+    if std::io::stderr().is_terminal() {
+        archive.set_observer(Arc::new(ProgressBarObserver::new()));
+    }
+    let token = archive.cancellation_token();
+    ctrlc::set_handler(move || {
+        if token.is_cancelled() {
+            std::process::exit(130);
+        }
+        token.cancel();
+    })?;
+    Ok(())
+}
+
+fn check_config(sources: &ConfigSources) -> Result<(), MoonBallError> {
+    let report = load_config_report(sources);
+    println!("Configuration layers (lowest precedence first):");
+    for layer in &report.layers {
+        println!("  {}", layer);
+    }
+    for warning in &report.warnings {
+        println!("warning: {}", warning);
+    }
+    if report.errors.is_empty() {
+        println!("Configuration is valid.");
+        return Ok(());
+    }
+    for err in &report.errors {
+        println!("error: {}", err);
+    }
+    Err(MoonBallError::Config(format!(
+        "{} error(s), see above",
+        report.errors.len()
+    )))
+}
+
+/// The archive and chunk the current thread is working on, attached to its log records.
+#[derive(Default, Clone)]
+struct LogContext {
+    archive: Option<String>,
+    chunk: Option<(String, usize)>, // File name and chunk ID
+}
+
+thread_local! {
+    static LOG_CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/// Sets part of this thread's log context until dropped.
+struct LogScope {
+    previous: LogContext,
+}
+
+impl LogScope {
+    fn archive(archive_path: &str) -> Self {
+        Self::update(|context| context.archive = Some(archive_path.to_string()))
+    }
+
+    fn chunk(file_name: &str, chunk_id: usize) -> Self {
+        Self::update(|context| context.chunk = Some((file_name.to_string(), chunk_id)))
+    }
+
+    fn update(change: impl FnOnce(&mut LogContext)) -> Self {
+        let previous = LOG_CONTEXT.with(|context| {
+            let previous = context.borrow().clone();
+            change(&mut context.borrow_mut());
+            previous
+        });
+        LogScope { previous }
+    }
+}
+
+impl Drop for LogScope {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        LOG_CONTEXT.with(|context| *context.borrow_mut() = previous);
+    }
+}
+
+/// A log file that is renamed to `<path>.1` once it reaches `max_size`, shifting
+/// older backups up and deleting the one past `backup_count`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    backup_count: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, backup_count: u32) -> Result<Self, std::io::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            backup_count,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), std::io::Error> {
+        let length = line.len() as u64 + 1;
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), std::io::Error> {
+        if self.backup_count == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let backup = |number: u32| PathBuf::from(format!("{}.{}", self.path.display(), number));
+            let _ = std::fs::remove_file(backup(self.backup_count));
+            for number in (1..self.backup_count).rev() {
+                let _ = std::fs::rename(backup(number), backup(number + 1));
             }
+            std::fs::rename(&self.path, backup(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
         }
+        self.size = 0;
+        Ok(())
+    }
+}
 
-        Ok([salt.to_vec(), iv.to_vec(), final_result].concat())
+/// Logs to stderr and, if configured, to a rotating file.
+struct MoonBallLogger {
+    level: log::LevelFilter,
+    console: env_logger::Logger,
+    file: Option<Mutex<RotatingFile>>,
+    json: bool,
+}
+
+impl log::Log for MoonBallLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level || self.console.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        // The console also honours module filters from RUST_LOG
+        self.console.log(record);
+        if record.level() > self.level {
+            return;
+        }
+        if let Some(file) = &self.file {
+            let line =
+                LOG_CONTEXT.with(|context| format_log_line(record, &context.borrow(), self.json));
+            // A full disk shouldn't fail the operation being logged
+            let _ = file.lock().unwrap().write_line(&line);
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+fn format_log_line(record: &log::Record, context: &LogContext, json: bool) -> String {
+    let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+    if json {
+        let mut entry = serde_json::json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        if let Some(archive) = &context.archive {
+            entry["archive"] = archive.as_str().into();
+        }
+        if let Some((file_name, chunk_id)) = &context.chunk {
+            entry["file"] = file_name.as_str().into();
+            entry["chunk_id"] = (*chunk_id).into();
+        }
+        return entry.to_string();
+    }
+
+    let mut line = format!("{} {:<5} {}", timestamp, record.level(), record.target());
+    if let Some(archive) = &context.archive {
+        line.push_str(&format!(" [{}]", archive));
+    }
+    if let Some((file_name, chunk_id)) = &context.chunk {
+        line.push_str(&format!(" [{}:{}]", file_name, chunk_id));
+    }
+    format!("{}: {}", line, record.args())
+}
+
+/// Installs the global logger from `logging_level` and the `logging` settings. An
+/// invalid configuration falls back to the defaults here; it is reported when the
+/// command loads it.
+fn init_logging(sources: &ConfigSources) {
+    let config = load_config_report(sources).config.unwrap_or_default();
+    let level = match config.logging_level.to_uppercase().as_str() {
+        "DEBUG" => log::LevelFilter::Debug,
+        "WARNING" => log::LevelFilter::Warn,
+        "ERROR" => log::LevelFilter::Error,
+        _ => log::LevelFilter::Info,
+    };
+
+    let logging = &config.logging;
+    let mut file_error = None;
+    let file = if logging.file_path.is_empty() {
+        None
+    } else {
+        match RotatingFile::open(
+            Path::new(&logging.file_path),
+            logging.max_size_mb as u64 * 1024 * 1024,
+            logging.backup_count,
+        ) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(err) => {
+                file_error = Some(err);
+                None
+            }
+        }
+    };
+
+    let logger = MoonBallLogger {
+        level,
+        console: env_logger::Builder::from_default_env()
+            .filter_level(level)
+            .build(),
+        file,
+        json: logging.json,
+    };
+    let max_level = level.max(logger.console.filter());
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+    if let Some(err) = file_error {
+        log::warn!(
+            "Could not open log file {}: {}; logging to the console only",
+            logging.file_path,
+            err
+        );
+    }
+}
+
+/// Progress of a background GUI job, shared with the UI thread.
+#[derive(Default)]
+struct GuiJob {
+    label: String,
+    progress: Option<Progress>, // Latest update from the archive, if the job reports any
+    cancellation: CancellationToken,
+    result: Option<Result<JobOutput, String>>,
+}
+
+/// Forwards archive progress to the GUI job and wakes the UI.
+struct GuiObserver {
+    job: Arc<Mutex<GuiJob>>,
+    ctx: egui::Context,
+}
+
+impl ProgressObserver for GuiObserver {
+    fn on_progress(&self, progress: &Progress) {
+        self.job.lock().unwrap().progress = Some(progress.clone());
+        self.ctx.request_repaint();
+    }
+}
+
+enum JobOutput {
+    Saved {
+        path: String,
+        secret: Option<String>,
+        summary: String,
+    },
+    Opened {
+        path: String,
+        entries: Vec<FileSummary>,
+    },
+    Extracted {
+        output_dir: String,
+        summary: String,
+    },
+    Searched(Vec<SearchHit>),
+}
+
+/// Directory tree of archive entries, built from their stored paths.
+#[derive(Default)]
+struct ArchiveTree {
+    directories: BTreeMap<String, ArchiveTree>,
+    files: Vec<usize>, // Indexes into the entry list
+}
+
+impl ArchiveTree {
+    fn build(entries: &[FileSummary]) -> Self {
+        let mut root = ArchiveTree::default();
+        for (index, entry) in entries.iter().enumerate() {
+            let mut parts: Vec<&str> = entry
+                .path
+                .split(['/', '\\'])
+                .filter(|part| !part.is_empty())
+                .collect();
+            parts.pop();
+            let mut node = &mut root;
+            for part in parts {
+                node = node.directories.entry(part.to_string()).or_default();
+            }
+            node.files.push(index);
+        }
+        root
+    }
+
+    /// Appends the visible rows below `path`; only directories in `expanded` are opened.
+    fn rows(&self, path: &str, depth: usize, expanded: &HashSet<String>, rows: &mut Vec<TuiRow>) {
+        for (name, child) in &self.directories {
+            let child_path = format!("{}{}/", path, name);
+            let open = expanded.contains(&child_path);
+            rows.push(TuiRow::Directory {
+                path: child_path.clone(),
+                name: name.clone(),
+                depth,
+                open,
+            });
+            if open {
+                child.rows(&child_path, depth + 1, expanded, rows);
+            }
+        }
+        for &index in &self.files {
+            rows.push(TuiRow::File { index, depth });
+        }
+    }
+
+    fn show(
+        &self,
+        ui: &mut egui::Ui,
+        path: &str,
+        entries: &[FileSummary],
+        selected: &mut HashSet<String>,
+    ) {
+        for (name, child) in &self.directories {
+            let child_path = format!("{}{}/", path, name);
+            egui::CollapsingHeader::new(format!("📁 {}", name))
+                .id_source(&child_path)
+                .show(ui, |ui| child.show(ui, &child_path, entries, selected));
+        }
+        for &index in &self.files {
+            let entry = &entries[index];
+            let name = entry.path.rsplit(['/', '\\']).next().unwrap_or(&entry.path);
+            let mut checked = selected.contains(&entry.path);
+            let label = format!(
+                "{}  ({}, {} chunks, {})",
+                name,
+                format_size(entry.size),
+                entry.chunks,
+                entry.codecs.join(", ")
+            );
+            if ui.checkbox(&mut checked, label).changed() {
+                if checked {
+                    selected.insert(entry.path.clone());
+                } else {
+                    selected.remove(&entry.path);
+                }
+            }
+        }
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Lays out a snippet with the regions matching the query highlighted.
+fn snippet_layout(snippet: &Snippet, ui: &egui::Ui) -> egui::text::LayoutJob {
+    let normal = egui::TextFormat {
+        color: ui.visuals().text_color(),
+        ..Default::default()
+    };
+    let highlighted = egui::TextFormat {
+        color: ui.visuals().strong_text_color(),
+        background: ui.visuals().selection.bg_fill,
+        ..Default::default()
+    };
+    let mut job = egui::text::LayoutJob::default();
+    let mut last = 0;
+    for highlight in &snippet.highlights {
+        job.append(&snippet.text[last..highlight.start], 0.0, normal.clone());
+        job.append(&snippet.text[highlight.clone()], 0.0, highlighted.clone());
+        last = highlight.end;
+    }
+    job.append(&snippet.text[last..], 0.0, normal);
+    job
+}
+
+/// The desktop GUI. Archive work runs on a background thread so the window stays
+/// responsive; at most one job runs at a time.
+struct MoonBallApp {
+    sources: ConfigSources,
+    pending: Vec<String>, // Files and folders for the next archive
+    scheme: Scheme,
+    encrypt: bool,
+    two_factor: bool,
+    archive_path: Option<String>,
+    entries: Vec<FileSummary>,
+    tree: ArchiveTree,
+    selected: HashSet<String>, // Paths of the entries ticked for extraction
+    otp: String,
+    query: String,
+    top_k: usize,
+    hits: Vec<SearchHit>,
+    status: String,
+    job: Option<Arc<Mutex<GuiJob>>>,
+}
+
+impl MoonBallApp {
+    fn new(sources: ConfigSources) -> Self {
+        let (scheme, two_factor, status) = match load_config(&sources) {
+            Ok(config) => (
+                config.scheme.parse().unwrap_or(Scheme::Balanced),
+                config.two_factor_authentication.enabled,
+                "Drag files or folders into the window to add them".to_string(),
+            ),
+            Err(err) => (Scheme::Balanced, false, err.to_string()),
+        };
+        MoonBallApp {
+            sources,
+            pending: Vec::new(),
+            scheme,
+            encrypt: false,
+            two_factor,
+            archive_path: None,
+            entries: Vec::new(),
+            tree: ArchiveTree::default(),
+            selected: HashSet::new(),
+            otp: String::new(),
+            query: String::new(),
+            top_k: 10,
+            hits: Vec::new(),
+            status,
+            job: None,
+        }
+    }
+
+    fn add_pending(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            let path = path.to_string_lossy().into_owned();
+            if !self.pending.contains(&path) {
+                self.pending.push(path);
+            }
+        }
+    }
+
+    /// Runs `work` on a background thread with a freshly configured archive whose
+    /// progress and cancellation are bound to the job.
+    fn spawn<F>(&mut self, ctx: &egui::Context, label: &str, work: F)
+    where
+        F: FnOnce(&mut MoonBallArchive) -> Result<JobOutput, MoonBallError> + Send + 'static,
+    {
+        let job = Arc::new(Mutex::new(GuiJob {
+            label: label.to_string(),
+            ..GuiJob::default()
+        }));
+        self.job = Some(job.clone());
+        let (sources, ctx) = (self.sources.clone(), ctx.clone());
+        std::thread::spawn(move || {
+            let result = load_config(&sources).and_then(|config| {
+                let mut archive = MoonBallArchive::with_config(config);
+                archive.set_observer(Arc::new(GuiObserver {
+                    job: job.clone(),
+                    ctx: ctx.clone(),
+                }));
+                archive.set_cancellation_token(job.lock().unwrap().cancellation.clone());
+                work(&mut archive)
+            });
+            job.lock().unwrap().result = Some(result.map_err(|err| err.to_string()));
+            ctx.request_repaint();
+        });
+    }
+
+    fn poll_job(&mut self) {
+        let Some(job) = &self.job else { return };
+        let Some(result) = job.lock().unwrap().result.take() else {
+            return;
+        };
+        self.job = None;
+        self.status = match result {
+            Ok(JobOutput::Saved {
+                path,
+                secret,
+                summary,
+            }) => {
+                self.pending.clear();
+                match secret {
+                    Some(secret) => format!(
+                        "Saved {}. {}. Add this 2FA secret to your authenticator app: {}",
+                        path, summary, secret
+                    ),
+                    None => format!("Saved {}. {}", path, summary),
+                }
+            }
+            Ok(JobOutput::Opened { path, entries }) => {
+                let status = format!("Opened {} ({} files)", path, entries.len());
+                self.tree = ArchiveTree::build(&entries);
+                self.entries = entries;
+                self.archive_path = Some(path);
+                self.selected.clear();
+                self.hits.clear();
+                status
+            }
+            Ok(JobOutput::Extracted {
+                output_dir,
+                summary,
+            }) => format!("{} to {}", summary, output_dir),
+            Ok(JobOutput::Searched(hits)) => {
+                self.hits = hits;
+                format!("{} results", self.hits.len())
+            }
+            Err(err) => {
+                error!("{}", err);
+                format!("Error: {}", err)
+            }
+        };
+    }
+
+    fn save(&mut self, ctx: &egui::Context) {
+        let Some(output) = rfd::FileDialog::new()
+            .add_filter("MoonBall archive", &["mnbl"])
+            .set_file_name("archive.mnbl")
+            .save_file()
+        else {
+            return;
+        };
+        let output = output.to_string_lossy().into_owned();
+        let (pending, scheme, encrypt, two_factor) = (
+            self.pending.clone(),
+            self.scheme,
+            self.encrypt,
+            self.two_factor,
+        );
+        self.spawn(ctx, "Collecting files", move |archive| {
+            archive.set_scheme(scheme);
+            if encrypt {
+                archive.enable_encryption();
+            }
+            let secret = archive.set_two_factor(two_factor);
+            let paths: Vec<&str> = pending.iter().map(String::as_str).collect();
+            archive.add_paths(&paths)?;
+            archive.save_archive(&output)?;
+            info!("Archive saved as {}", output);
+            Ok(JobOutput::Saved {
+                summary: archive.report().summary(),
+                path: output,
+                secret,
+            })
+        });
+    }
+
+    fn open(&mut self, ctx: &egui::Context) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("MoonBall archive", &["mnbl", "🌕"])
+            .pick_file()
+        else {
+            return;
+        };
+        let path = path.to_string_lossy().into_owned();
+        self.spawn(ctx, "Reading index", move |archive| {
+            let entries = archive.list_files(&path)?;
+            Ok(JobOutput::Opened { path, entries })
+        });
+    }
+
+    fn extract(&mut self, ctx: &egui::Context) {
+        let Some(archive_path) = self.archive_path.clone() else {
+            return;
+        };
+        let Some(output_dir) = rfd::FileDialog::new().pick_folder() else {
+            return;
+        };
+        let output_dir = output_dir.to_string_lossy().into_owned();
+        let (selected, otp) = (self.selected.clone(), self.otp.clone());
+        self.spawn(ctx, "Extracting", move |archive| {
+            if !otp.trim().is_empty() {
+                archive.set_otp(&otp);
+            }
+            archive.extract_files(&archive_path, &output_dir, &selected)?;
+            Ok(JobOutput::Extracted {
+                summary: archive.report().summary(),
+                output_dir,
+            })
+        });
+    }
+
+    fn search(&mut self, ctx: &egui::Context) {
+        let Some(archive_path) = self.archive_path.clone() else {
+            return;
+        };
+        let (query, top_k, otp) = (self.query.clone(), self.top_k, self.otp.clone());
+        self.spawn(ctx, "Searching", move |archive| {
+            if !otp.trim().is_empty() {
+                archive.set_otp(&otp);
+            }
+            Ok(JobOutput::Searched(archive.search(
+                &archive_path,
+                &query,
+                top_k,
+            )?))
+        });
+    }
+
+    fn show_create(&mut self, ui: &mut egui::Ui, ctx: &egui::Context, hovering: bool) {
+        ui.heading("New archive");
+        ui.horizontal(|ui| {
+            if ui.button("Add Files").clicked() {
+                if let Some(paths) = rfd::FileDialog::new().pick_files() {
+                    self.add_pending(paths);
+                }
+            }
+            if ui.button("Add Directory").clicked() {
+                if let Some(path) = rfd::FileDialog::new().pick_folder() {
+                    self.add_pending([path]);
+                }
+            }
+            if ui
+                .add_enabled(!self.pending.is_empty(), egui::Button::new("Clear"))
+                .clicked()
+            {
+                self.pending.clear();
+            }
+        });
+
+        egui::ScrollArea::vertical()
+            .id_source("pending")
+            .max_height(240.0)
+            .show(ui, |ui| {
+                if self.pending.is_empty() {
+                    ui.weak(if hovering {
+                        "Drop to add"
+                    } else {
+                        "Drag files or folders here"
+                    });
+                }
+                let mut removed = None;
+                for (index, path) in self.pending.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        if ui.small_button("✖").clicked() {
+                            removed = Some(index);
+                        }
+                        ui.label(path);
+                    });
+                }
+                if let Some(index) = removed {
+                    self.pending.remove(index);
+                }
+            });
+
+        ui.separator();
+        egui::ComboBox::from_label("Scheme")
+            .selected_text(format!("{:?}", self.scheme))
+            .show_ui(ui, |ui| {
+                for scheme in [Scheme::Fast, Scheme::Balanced, Scheme::Max] {
+                    ui.selectable_value(&mut self.scheme, scheme, format!("{:?}", scheme));
+                }
+            });
+        ui.checkbox(&mut self.encrypt, "Encrypt");
+        ui.checkbox(&mut self.two_factor, "Require 2FA to extract");
+        let ready = self.job.is_none() && !self.pending.is_empty();
+        if ui
+            .add_enabled(ready, egui::Button::new("Compress to Archive"))
+            .clicked()
+        {
+            self.save(ctx);
+        }
+    }
+
+    fn show_archive(&mut self, ui: &mut egui::Ui, ctx: &egui::Context) {
+        let idle = self.job.is_none();
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(idle, egui::Button::new("Open Archive"))
+                .clicked()
+            {
+                self.open(ctx);
+            }
+            if let Some(path) = &self.archive_path {
+                ui.label(path);
+            }
+        });
+        if self.archive_path.is_none() {
+            return;
+        }
+
+        ui.horizontal(|ui| {
+            let response = ui.text_edit_singleline(&mut self.query);
+            let submitted =
+                response.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter));
+            ui.add(
+                egui::DragValue::new(&mut self.top_k)
+                    .clamp_range(1..=100)
+                    .prefix("top "),
+            );
+            let clicked = ui
+                .add_enabled(idle, egui::Button::new("Semantic Search"))
+                .clicked();
+            if (clicked || submitted) && idle && !self.query.trim().is_empty() {
+                self.search(ctx);
+            }
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("Select All").clicked() {
+                self.selected = self
+                    .entries
+                    .iter()
+                    .map(|entry| entry.path.clone())
+                    .collect();
+            }
+            if ui.button("Select None").clicked() {
+                self.selected.clear();
+            }
+            ui.label("OTP");
+            ui.add(egui::TextEdit::singleline(&mut self.otp).desired_width(80.0));
+            let label = format!("Extract Selected ({})", self.selected.len());
+            if ui
+                .add_enabled(idle && !self.selected.is_empty(), egui::Button::new(label))
+                .clicked()
+            {
+                self.extract(ctx);
+            }
+        });
+        ui.separator();
+
+        let (tree, entries, selected, hits) =
+            (&self.tree, &self.entries, &mut self.selected, &self.hits);
+        ui.columns(2, |columns| {
+            egui::ScrollArea::vertical()
+                .id_source("tree")
+                .show(&mut columns[0], |ui| tree.show(ui, "", entries, selected));
+            egui::ScrollArea::vertical()
+                .id_source("hits")
+                .show(&mut columns[1], |ui| {
+                    for hit in hits {
+                        ui.group(|ui| {
+                            ui.horizontal(|ui| {
+                                // Clicking a hit ticks its file for extraction
+                                if ui
+                                    .link(format!("{}:{}", hit.file_name, hit.chunk_id))
+                                    .clicked()
+                                {
+                                    selected.insert(hit.file_name.clone());
+                                }
+                                ui.weak(format!(
+                                    "bytes {}..{}  score {:.3}",
+                                    hit.byte_range.start, hit.byte_range.end, hit.score
+                                ));
+                            });
+                            if let Some(snippet) = &hit.snippet {
+                                let layout = snippet_layout(snippet, ui);
+                                ui.label(layout);
+                            }
+                        });
+                    }
+                });
+        });
+    }
+}
+
+impl eframe::App for MoonBallApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_job();
+        let dropped: Vec<PathBuf> = ctx.input(|input| {
+            input
+                .raw
+                .dropped_files
+                .iter()
+                .filter_map(|file| file.path.clone())
+                .collect()
+        });
+        self.add_pending(dropped);
+        let hovering = ctx.input(|input| !input.raw.hovered_files.is_empty());
+
+        egui::TopBottomPanel::bottom("status").show(ctx, |ui| match &self.job {
+            Some(job) => {
+                let job = job.lock().unwrap();
+                ui.horizontal(|ui| {
+                    match job
+                        .progress
+                        .as_ref()
+                        .filter(|progress| progress.bytes_total > 0)
+                    {
+                        Some(progress) => {
+                            if ui.button("Cancel").clicked() {
+                                job.cancellation.cancel();
+                            }
+                            let eta = progress
+                                .eta()
+                                .map_or(String::new(), |eta| format!(", {}s left", eta.as_secs()));
+                            let text = format!(
+                                "{} {} ({} of {}{})",
+                                progress.operation,
+                                progress.current_file,
+                                format_size(progress.bytes_done),
+                                format_size(progress.bytes_total),
+                                eta
+                            );
+                            let fraction = progress.bytes_done as f32 / progress.bytes_total as f32;
+                            ui.add(egui::ProgressBar::new(fraction).text(text));
+                        }
+                        None => {
+                            ui.spinner();
+                            ui.label(&job.label);
+                        }
+                    }
+                });
+            }
+            None => {
+                ui.label(&self.status);
+            }
+        });
+        egui::SidePanel::left("create")
+            .min_width(280.0)
+            .show(ctx, |ui| self.show_create(ui, ctx, hovering));
+        egui::CentralPanel::default().show(ctx, |ui| self.show_archive(ui, ctx));
+    }
+}
+
+/// Opens the desktop GUI, the counterpart of the Python version's Tk window.
+fn launch_gui(sources: ConfigSources) -> Result<(), MoonBallError> {
+    let app = MoonBallApp::new(sources);
+    eframe::run_native(
+        "MoonBall Archiver",
+        eframe::NativeOptions::default(),
+        Box::new(|_cc| Box::new(app)),
+    )?;
+    Ok(())
+}
+
+/// A line of the terminal browser's tree: a directory or a file, indented by depth.
+enum TuiRow {
+    Directory {
+        path: String,
+        name: String,
+        depth: usize,
+        open: bool,
+    },
+    File {
+        index: usize,
+        depth: usize,
+    },
+}
+
+/// Normalizes a stored file name to the `/`-separated form used by `ArchiveTree`.
+fn tree_path(path: &str) -> String {
+    path.split(['/', '\\'])
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// State of the `--tui` browser. It holds the same snapshot chain and index that
+/// extraction uses, so previews and extraction read chunks through `load_chunk`.
+struct TuiState {
+    archive_path: String,
+    output_dir: String,
+    chain: Vec<(String, ArchiveMetadata)>,
+    entries: Vec<FileSummary>,
+    tree: ArchiveTree,
+    expanded: HashSet<String>, // Directory paths shown open, ending in `/`
+    rows: Vec<TuiRow>,
+    hits: Vec<SearchHit>,
+    showing_hits: bool, // The list shows search results instead of the tree
+    list: ratatui::widgets::ListState,
+    input: Option<String>, // Search query while it is being typed
+    chunk_id: usize,       // Chunk of the selected file shown in the preview
+    preview: String,
+    preview_title: String,
+    scroll: u16,
+    block_cache: Option<(String, Vec<u8>)>,
+    status: String,
+}
+
+impl TuiState {
+    fn new(archive_path: &str, output_dir: &str, chain: Vec<(String, ArchiveMetadata)>) -> Self {
+        let entries = chain[0].1.file_summaries();
+        let tree = ArchiveTree::build(&entries);
+
+        // Open the leading run of single directories so the first screen shows files
+        let mut expanded = HashSet::new();
+        let mut node = &tree;
+        let mut path = String::new();
+        while node.files.is_empty() && node.directories.len() == 1 {
+            let (name, child) = node.directories.iter().next().unwrap();
+            path = format!("{}{}/", path, name);
+            expanded.insert(path.clone());
+            node = child;
+        }
+
+        let mut state = TuiState {
+            archive_path: archive_path.to_string(),
+            output_dir: output_dir.to_string(),
+            status: format!(
+                "{} files in {}, press ? for help",
+                entries.len(),
+                archive_path
+            ),
+            chain,
+            entries,
+            tree,
+            expanded,
+            rows: Vec::new(),
+            hits: Vec::new(),
+            showing_hits: false,
+            list: ratatui::widgets::ListState::default(),
+            input: None,
+            chunk_id: 0,
+            preview: String::new(),
+            preview_title: String::new(),
+            scroll: 0,
+            block_cache: None,
+        };
+        state.rebuild_rows();
+        state.list.select(Some(0));
+        state
+    }
+
+    fn rebuild_rows(&mut self) {
+        self.rows.clear();
+        self.tree.rows("", 0, &self.expanded, &mut self.rows);
+    }
+
+    fn len(&self) -> usize {
+        if self.showing_hits {
+            self.hits.len()
+        } else {
+            self.rows.len()
+        }
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        if self.len() == 0 {
+            return;
+        }
+        let current = self.list.selected().unwrap_or(0) as isize;
+        self.list.select(Some(
+            (current + delta).clamp(0, self.len() as isize - 1) as usize
+        ));
+        self.chunk_id = 0;
+    }
+
+    /// Opens or closes the selected directory. Closing from a file closes its parent.
+    fn expand(&mut self, open: bool) {
+        if self.showing_hits {
+            return;
+        }
+        let Some(row) = self
+            .list
+            .selected()
+            .and_then(|selected| self.rows.get(selected))
+        else {
+            return;
+        };
+        let path = match row {
+            TuiRow::Directory { path, .. } => path.clone(),
+            TuiRow::File { index, .. } if !open => {
+                match tree_path(&self.entries[*index].path).rsplit_once('/') {
+                    Some((parent, _)) => format!("{}/", parent),
+                    None => return,
+                }
+            }
+            TuiRow::File { .. } => return,
+        };
+        if open {
+            self.expanded.insert(path);
+            self.rebuild_rows();
+        } else {
+            self.expanded.remove(&path);
+            self.rebuild_rows();
+            let row = self.rows.iter().position(
+                |row| matches!(row, TuiRow::Directory { path: other, .. } if *other == path),
+            );
+            self.list.select(row.or(Some(0)));
+        }
+    }
+
+    /// File name and chunk ID under the cursor, if it is a file or search hit.
+    fn selected_chunk(&self) -> Option<(String, usize)> {
+        let selected = self.list.selected()?;
+        if self.showing_hits {
+            let hit = self.hits.get(selected)?;
+            return Some((hit.file_name.clone(), hit.chunk_id));
+        }
+        match self.rows.get(selected)? {
+            TuiRow::File { index, .. } => Some((self.entries[*index].path.clone(), self.chunk_id)),
+            TuiRow::Directory { .. } => None,
+        }
+    }
+
+    fn step_chunk(&mut self, delta: isize) {
+        let Some(selected) = self.list.selected() else {
+            return;
+        };
+        if let Some(TuiRow::File { index, .. }) =
+            self.rows.get(selected).filter(|_| !self.showing_hits)
+        {
+            let last = self.entries[*index].chunks.saturating_sub(1) as isize;
+            self.chunk_id = (self.chunk_id as isize + delta).clamp(0, last) as usize;
+        }
+    }
+
+    fn load_preview(&mut self, archive: &MoonBallArchive) {
+        self.scroll = 0;
+        let Some((file_name, chunk_id)) = self.selected_chunk() else {
+            self.preview_title = "Preview".to_string();
+            self.preview = match self
+                .list
+                .selected()
+                .and_then(|selected| self.rows.get(selected))
+            {
+                Some(TuiRow::Directory { path, .. }) => {
+                    format!("{}\n\nPress x to extract this directory.", path)
+                }
+                _ => String::new(),
+            };
+            return;
+        };
+        let Some(chunk) = self.chain[0].1.find_chunk(&file_name, chunk_id).cloned() else {
+            return;
+        };
+        let start = self.chain[0].1.file_offset(&chunk);
+        let chunks = self.chain[0]
+            .1
+            .chunks
+            .iter()
+            .filter(|other| other.file_name == file_name)
+            .count();
+        self.preview_title = format!(
+            "{} [chunk {}/{}, {}, bytes {}..{}]",
+            file_name,
+            chunk_id + 1,
+            chunks,
+            chunk_codec(&chunk),
+            start,
+            start + chunk.original_size as u64
+        );
+        self.preview = match archive.load_chunk(&self.chain, &chunk, &mut self.block_cache) {
+            Ok(data)
+                if FileCategory::detect(&file_name, &data[..data.len().min(4096)]).is_textual() =>
+            {
+                String::from_utf8_lossy(&data[..data.len().min(TUI_PREVIEW_LIMIT)]).into_owned()
+            }
+            Ok(data) => format!("Binary chunk, {} bytes", data.len()),
+            Err(err) => format!("Could not read chunk: {}", err),
+        };
+    }
+
+    fn extract(&mut self, archive: &MoonBallArchive, all: bool) {
+        let selected = self.list.selected();
+        let result = if all {
+            archive.extract_chunks(&self.chain, &self.output_dir, &|_| true)
+        } else if self.showing_hits {
+            let Some(hit) = selected.and_then(|selected| self.hits.get(selected)) else {
+                return;
+            };
+            archive.extract_chunks(&self.chain, &self.output_dir, &|chunk| {
+                chunk.file_name == hit.file_name
+            })
+        } else {
+            match selected.and_then(|selected| self.rows.get(selected)) {
+                Some(TuiRow::File { index, .. }) => {
+                    let file_name = &self.entries[*index].path;
+                    archive.extract_chunks(&self.chain, &self.output_dir, &|chunk| {
+                        chunk.file_name == *file_name
+                    })
+                }
+                Some(TuiRow::Directory { path, .. }) => {
+                    archive.extract_chunks(&self.chain, &self.output_dir, &|chunk| {
+                        tree_path(&chunk.file_name).starts_with(path.as_str())
+                    })
+                }
+                None => return,
+            }
+        };
+        self.status = match result {
+            Ok(count) => format!("Extracted {} files to {}", count, self.output_dir),
+            Err(err) => format!("Extraction failed: {}", err),
+        };
     }
 
-    fn decrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let salt = &data[..16];
-        let iv = &data[16..32];
-        let kdf = Hmac::<Sha256>::new_from_slice(b"moonball").unwrap();
-        let mut mac = kdf.clone();
-        mac.update(salt);
-        let key = mac.finalize().into_bytes();
+    fn draw(&mut self, frame: &mut ratatui::Frame) {
+        use ratatui::layout::{Constraint, Direction, Layout};
+        use ratatui::style::{Modifier, Style};
+        use ratatui::text::{Line, Span};
+        use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph, Wrap};
 
-        let cipher = cbc_decryptor(KeySize::KeySize256, &key[..], iv, crypto::blockmodes::PkcsPadding);
-        let mut final_result = Vec::<u8>::new();
-        let mut read_buffer = RefReadBuffer::new(&data[32..]);
-        let mut buffer = [0; 4096];
-        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+        let outer = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(frame.size());
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+            .split(outer[0]);
 
-        loop {
-            let result = cipher.decrypt(&mut read_buffer, &mut write_buffer, true)?;
-            final_result.extend(write_buffer.take_read_buffer().take_remaining());
-            match result {
-                BufferResult::BufferUnderflow => break,
-                BufferResult::BufferOverflow => {}
-            }
-        }
+        let highlight = Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED);
+        let (title, items): (String, Vec<ListItem>) = if self.showing_hits {
+            let items = self
+                .hits
+                .iter()
+                .map(|hit| {
+                    let mut lines = vec![Line::from(format!(
+                        "{}:{}  {:.3}",
+                        hit.file_name, hit.chunk_id, hit.score
+                    ))];
+                    if let Some(snippet) = &hit.snippet {
+                        let mut spans = vec![Span::raw("  ")];
+                        let mut last = 0;
+                        for range in &snippet.highlights {
+                            spans.push(Span::raw(
+                                snippet.text[last..range.start].replace('\n', " "),
+                            ));
+                            spans.push(Span::styled(
+                                snippet.text[range.clone()].to_string(),
+                                highlight,
+                            ));
+                            last = range.end;
+                        }
+                        spans.push(Span::raw(snippet.text[last..].replace('\n', " ")));
+                        lines.push(Line::from(spans));
+                    }
+                    ListItem::new(lines)
+                })
+                .collect();
+            (format!("Results ({})", self.hits.len()), items)
+        } else {
+            let items = self
+                .rows
+                .iter()
+                .map(|row| match row {
+                    TuiRow::Directory {
+                        name, depth, open, ..
+                    } => ListItem::new(format!(
+                        "{}{} {}/",
+                        "  ".repeat(*depth),
+                        if *open { "▾" } else { "▸" },
+                        name
+                    )),
+                    TuiRow::File { index, depth } => {
+                        let entry = &self.entries[*index];
+                        let name = entry.path.rsplit(['/', '\\']).next().unwrap_or(&entry.path);
+                        ListItem::new(format!(
+                            "{}  {}  {}  {}",
+                            "  ".repeat(*depth),
+                            name,
+                            format_size(entry.size),
+                            entry.codecs.join(",")
+                        ))
+                    }
+                })
+                .collect();
+            (self.archive_path.clone(), items)
+        };
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, panes[0], &mut self.list);
 
-        Ok(final_result)
-    }
+        let preview = Paragraph::new(self.preview.as_str())
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(self.preview_title.as_str()),
+            )
+            .wrap(Wrap { trim: false })
+            .scroll((self.scroll, 0));
+        frame.render_widget(preview, panes[1]);
 
-    fn ask_for_otp(&self) -> Option<String> {
-        println!("Enter 2FA OTP: ");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).ok()?;
-        Some(input.trim().to_string())
+        let status = match &self.input {
+            Some(input) => format!("/{}", input),
+            None => self.status.clone(),
+        };
+        frame.render_widget(Paragraph::new(status), outer[1]);
+        if let Some(input) = &self.input {
+            frame.set_cursor(outer[1].x + 1 + input.chars().count() as u16, outer[1].y);
+        }
     }
 }
 
-fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
-    let config_path = "config.yml";
-    let file = File::open(config_path)?;
-    let reader = BufReader::new(file);
-    let config: Config = serde_yaml::from_reader(reader)?;
+const TUI_PREVIEW_LIMIT: usize = 64 * 1024; // Bytes of a text chunk shown in the preview pane
+const TUI_HELP: &str = "↑↓ move  →← open/close  [ ] chunk  PgUp/PgDn scroll  / search  x extract  X extract all  q quit";
 
-    Ok(config)
+/// Codec label for a chunk; members of a solid block share the block's codec.
+fn chunk_codec(chunk: &ChunkMetadata) -> &str {
+    if chunk.solid.is_some() {
+        "solid"
+    } else {
+        &chunk.compression_algo
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
+fn main() {
+    if let Err(err) = run() {
+        error!("{}", err);
+        std::process::exit(err.exit_code());
+    }
+}
 
+fn run() -> Result<(), MoonBallError> {
     let matches = App::new("MoonBall Archiver")
-        .version("1.0")
+        .version(VERSION)
         .author("Your Name <your.email@example.com>")
         .about("A Rust implementation of MoonBall Archiver")
         .arg(
@@ -431,71 +7889,1329 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .long("output")
                 .value_name("DIR/FILENAME")
                 .help("Output directory or archive name")
-                .takes_value(true)
-                .required_unless_one(&["add", "gui"]),
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("Configuration file layered over the user and project config")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Logging level (defaults to the `logging_level` config setting)")
+                .possible_values(LOGGING_LEVELS)
+                .ignore_case(true)
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("scheme")
                 .short('s')
                 .long("scheme")
-                .possible_values(&["fast", "balanced", "max"])
-                .default_value("balanced")
-                .help("Compression scheme"),
+                .possible_values(["fast", "balanced", "max"])
+                .takes_value(true)
+                .help("Compression scheme (defaults to the `scheme` config setting)"),
         )
         .arg(
             Arg::with_name("extension")
                 .short('x')
                 .long("extension")
-                .possible_values(&["mnbl", "🌕"])
+                .possible_values(["mnbl", "🌕"])
                 .default_value("mnbl")
                 .help("File extension for the archive"),
         )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .value_name("BASE_ARCHIVE")
+                .help("Create an incremental snapshot that only stores chunks changed since BASE_ARCHIVE")
+                .takes_value(true)
+                .requires("add"),
+        )
+        .arg(
+            Arg::with_name("solid")
+                .long("solid")
+                .help("Group small files into solid blocks that are compressed together")
+                .requires("add"),
+        )
+        .arg(
+            Arg::with_name("train-dict")
+                .long("train-dict")
+                .help("Train a zstd dictionary from the added files and store it in the archive")
+                .requires("add"),
+        )
         .arg(
             Arg::with_name("gui")
                 .short('g')
                 .long("gui")
                 .help("Launch graphical user interface"),
         )
+        .arg(
+            Arg::with_name("tui")
+                .long("tui")
+                .value_name("ARCHIVE")
+                .help("Browse an archive in a terminal UI; extracted files go to --output or the current directory")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("search")
                 .short('S')
                 .long("search")
                 .value_name("QUERY")
                 .help("Semantic search query")
+                .takes_value(true)
+                .requires("archive"),
+        )
+        .arg(
+            Arg::with_name("exact")
+                .long("exact")
+                .help("Fetch a wider candidate pool from the ANN graph and re-rank it by cosine similarity"),
+        )
+        .arg(
+            Arg::with_name("ask")
+                .long("ask")
+                .value_name("QUESTION")
+                .help("Answer a question from the archive's contents with the configured chat provider")
+                .takes_value(true)
+                .requires("archive"),
+        )
+        .arg(
+            Arg::with_name("agent")
+                .long("agent")
+                .value_name("TASK")
+                .help("Let the chat model work on a task using archive tools (list, search, read, extract, stats)")
+                .takes_value(true)
+                .requires("archive"),
+        )
+        .arg(
+            Arg::with_name("chat")
+                .long("chat")
+                .value_name("ARCHIVE")
+                .help("Start an interactive chat session about an archive")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("provider")
+                .long("provider")
+                .value_name("PROVIDER")
+                .help("Chat provider for --ask and --chat (ollama, openai)")
+                .possible_values(CHAT_PROVIDERS)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cat")
+                .long("cat")
+                .value_name("PATH[:CHUNK]")
+                .help("Print one decompressed chunk of a file (chunk 0 by default)")
+                .takes_value(true)
+                .requires("archive"),
+        )
+        .arg(
+            Arg::with_name("reindex")
+                .long("reindex")
+                .value_name("ARCHIVE")
+                .help("Recompute embeddings with the configured models without recompressing")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("missing")
+                .long("missing")
+                .help("Only embed chunks that were archived without an embedding")
+                .requires("reindex"),
+        )
+        .arg(
+            Arg::with_name("no-embeddings")
+                .long("no-embeddings")
+                .help("Archive without computing embeddings; add them later with --reindex --missing"),
+        )
+        .arg(
+            Arg::with_name("archive")
+                .short('A')
+                .long("archive")
+                .value_name("ARCHIVE")
+                .help("Archive to search or read from")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("top-k")
+                .short('k')
+                .long("top-k")
+                .value_name("N")
+                .help("Number of search results")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .subcommand(
+            App::new("serve")
+                .about("Serve archives to AI assistants")
+                .arg(
+                    Arg::with_name("mcp")
+                        .long("mcp")
+                        .help("Speak the Model Context Protocol over stdio")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("archives")
+                        .value_name("ARCHIVE")
+                        .help("Archives to serve")
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            App::new("config")
+                .about("Inspect the layered configuration")
+                .subcommand(App::new("check").about("Validate the effective configuration and list its sources")),
+        )
         .get_matches();
 
-    if matches.is_present("gui") {
-        // Launch GUI here (not implemented yet)
-        println!("GUI not implemented yet.");
+    let mut sources = ConfigSources {
+        config_path: matches.value_of("config").map(String::from),
+        overrides: Vec::new(),
+    };
+    if let Some(scheme) = matches.value_of("scheme") {
+        sources
+            .overrides
+            .push(("scheme".to_string(), scheme.to_string()));
+    }
+    if let Some(provider) = matches.value_of("provider") {
+        sources
+            .overrides
+            .push(("chat_provider".to_string(), provider.to_string()));
+    }
+    if matches.is_present("exact") {
+        sources
+            .overrides
+            .push(("semantic_search.ann.rerank".to_string(), "true".to_string()));
+    }
+    if let Some(level) = matches.value_of("log-level") {
+        sources
+            .overrides
+            .push(("logging_level".to_string(), level.to_string()));
+    }
+    init_logging(&sources);
+
+    if let Some(("config", config_matches)) = matches.subcommand() {
+        if config_matches.subcommand_matches("check").is_some() {
+            check_config(&sources)?;
+        }
+    } else if let Some(("serve", serve_matches)) = matches.subcommand() {
+        let archive_paths: Vec<&str> = serve_matches.values_of("archives").unwrap().collect();
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        archive.serve_mcp(&archive_paths)?;
+    } else if matches.is_present("gui") {
+        launch_gui(sources)?;
+    } else if let Some(archive_path) = matches.value_of("tui") {
+        let output_dir = matches.value_of("output").unwrap_or(".");
+        let top_k: usize = matches.value_of("top-k").unwrap().parse()?;
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        archive.browse(archive_path, output_dir, top_k)?;
     } else if let Some(files) = matches.values_of("add") {
-        let output_path = matches.value_of("output").unwrap();
+        let files: Vec<&str> = files.collect();
+        let output_path = matches
+            .value_of("output")
+            .ok_or("--output is required when adding files")?;
         let extension = matches.value_of("extension").unwrap();
 
-        let mut archive = MoonBallArchive::new();
-        for file in files {
-            if std::path::Path::new(file).is_dir() {
-                // Add directory logic here (not implemented yet)
-                println!("Adding directory: {}", file);
-            } else {
-                archive.add_file(file)?;
-            }
+        let mut archive = MoonBallArchive::with_config(load_config(&sources)?);
+        if let Some(base_path) = matches.value_of("snapshot") {
+            archive.set_snapshot_base(base_path)?;
+        }
+        if matches.is_present("solid") {
+            archive.enable_solid_mode();
         }
+        if matches.is_present("no-embeddings") {
+            archive.disable_embeddings();
+        }
+        if matches.is_present("train-dict") {
+            archive.train_dictionary(&files)?;
+        }
+        attach_cli_progress(&mut archive)?;
+        archive.add_paths(&files)?;
 
         let final_output_path = format!("{}.{}", output_path, extension);
         archive.save_archive(&final_output_path)?;
         info!("Archive saved as {}", final_output_path);
+        println!("{}", archive.report().summary());
     } else if let Some(archive_path) = matches.value_of("extract") {
-        let output_dir = matches.value_of("output").unwrap();
-        let mut archive = MoonBallArchive::new(); // Initialize with metadata
+        let output_dir = matches
+            .value_of("output")
+            .ok_or("--output is required when extracting")?;
+        let mut archive = MoonBallArchive::with_config(load_config(&sources)?);
+        attach_cli_progress(&mut archive)?;
         archive.extract(archive_path, output_dir)?;
         info!("Files extracted to {}", output_dir);
+        println!("{}", archive.report().summary());
+    } else if let Some(archive_path) = matches.value_of("reindex") {
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        let count = archive.reindex(archive_path, matches.is_present("missing"))?;
+        info!("Reindexed {} chunks in {}", count, archive_path);
     } else if let Some(query) = matches.value_of("search") {
-        let mut archive = MoonBallArchive::new(); // Initialize with metadata
-        let results = archive.semantic_search(query); // Implement semantic search in Rust (not implemented yet)
-        println!("Search results: {:?}", results);
+        let archive_path = matches.value_of("archive").unwrap();
+        let top_k: usize = matches.value_of("top-k").unwrap().parse()?;
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        print_hits(
+            &archive.search(archive_path, query, top_k)?,
+            std::io::stdout().is_terminal(),
+        );
+    } else if let Some(question) = matches.value_of("ask") {
+        let archive_path = matches.value_of("archive").unwrap();
+        let top_k: usize = matches.value_of("top-k").unwrap().parse()?;
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        let answer = archive.ask(archive_path, question, top_k)?;
+        println!("{}\n", answer.text);
+        print_sources(&answer.sources, false);
+    } else if let Some(task) = matches.value_of("agent") {
+        let archive_path = matches.value_of("archive").unwrap();
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        println!("{}", archive.run_agent(archive_path, task)?);
+    } else if let Some(archive_path) = matches.value_of("chat") {
+        let top_k: usize = matches.value_of("top-k").unwrap().parse()?;
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        archive.chat(archive_path, top_k)?;
+    } else if let Some(entry) = matches.value_of("cat") {
+        let archive_path = matches.value_of("archive").unwrap();
+        let (file_name, chunk_id) = parse_chunk_ref(entry);
+        let archive = MoonBallArchive::with_config(load_config(&sources)?);
+        archive.cat(
+            archive_path,
+            file_name,
+            chunk_id,
+            &mut std::io::stdout().lock(),
+        )?;
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Serves `count` HTTP requests on a local port, answering each with `body`, and
+    /// returns the base URL plus a handle yielding the raw requests it received.
+    fn stub_server(
+        body: &'static str,
+        count: usize,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_base = format!("http://{}", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for stream in listener.incoming().take(count) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut payload = vec![0; content_length];
+                reader.read_exact(&mut payload).unwrap();
+                request.push_str(&String::from_utf8(payload).unwrap());
+                requests.push(request);
+
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (api_base, handle)
+    }
+
+    fn chat_client(
+        provider: &str,
+        api_base: String,
+        api_key: &str,
+        context_length: usize,
+    ) -> ChatClient {
+        ChatClient {
+            provider: provider.to_string(),
+            api_base,
+            api_key: api_key.to_string(),
+            model: "test-model".to_string(),
+            context_length,
+            system_message: "You answer questions about archives.".to_string(),
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("moonball-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Archives one text file per entry of `contents` and returns the archive path.
+    fn text_archive(dir: &Path, contents: &[&str]) -> String {
+        let mut archive = MoonBallArchive::with_config(Config::default());
+        archive.disable_embeddings();
+        for (number, content) in contents.iter().enumerate() {
+            let file_path = dir.join(format!("note{}.txt", number));
+            std::fs::write(&file_path, content).unwrap();
+            archive.add_file(file_path.to_str().unwrap()).unwrap();
+        }
+        let archive_path = dir.join("notes.mnbl").to_string_lossy().into_owned();
+        archive.save_archive(&archive_path).unwrap();
+        archive_path
+    }
+
+    /// Reads back a file extracted to `output_dir` under its archived path.
+    fn extracted(output_dir: &Path, file_path: &Path) -> String {
+        let relative = safe_relative_path(file_path.to_str().unwrap()).unwrap();
+        std::fs::read_to_string(output_dir.join(relative)).unwrap()
+    }
+
+    /// Deterministic vectors scattered around 16 cluster centers, like embeddings of
+    /// related chunks.
+    fn clustered_vectors(count: usize, dimension: usize) -> Vec<Vec<f32>> {
+        let mut state = 0x2545_f491_u64;
+        let mut next = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+        };
+        let centers: Vec<Vec<f32>> = (0..16)
+            .map(|_| (0..dimension).map(|_| next()).collect())
+            .collect();
+        (0..count)
+            .map(|index| {
+                centers[index % centers.len()]
+                    .iter()
+                    .map(|value| value + next() * 0.05)
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn hits_for(metadata: &ArchiveMetadata) -> Vec<SearchHit> {
+        metadata
+            .chunks
+            .iter()
+            .map(|chunk| SearchHit::new(metadata, chunk, 1.0))
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_extracts_unchanged_and_changed_files() {
+        let dir = temp_dir("snapshot");
+        let files: Vec<PathBuf> = ["kept.txt", "edited.txt", "added.txt"]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        let paths: Vec<&str> = files.iter().map(|path| path.to_str().unwrap()).collect();
+        std::fs::write(&files[0], "unchanged between the two runs").unwrap();
+        std::fs::write(&files[1], "the first version").unwrap();
+
+        let base_path = dir.join("monday.mnbl").to_string_lossy().into_owned();
+        let mut base = MoonBallArchive::with_config(Config::default());
+        base.disable_embeddings();
+        base.add_paths(&paths[..2]).unwrap();
+        base.save_archive(&base_path).unwrap();
+
+        std::fs::write(&files[1], "the second version").unwrap();
+        std::fs::write(&files[2], "only in the snapshot").unwrap();
+        let snapshot_path = dir.join("tuesday.mnbl").to_string_lossy().into_owned();
+        let mut snapshot = MoonBallArchive::with_config(Config::default());
+        snapshot.disable_embeddings();
+        snapshot.set_snapshot_base(&base_path).unwrap();
+        snapshot.add_paths(&paths).unwrap();
+        snapshot.save_archive(&snapshot_path).unwrap();
+
+        let chain = snapshot.open_chain(&snapshot_path).unwrap();
+        assert_eq!(chain.len(), 2);
+        let base_ref = |path: &str| {
+            chain[0]
+                .1
+                .chunks
+                .iter()
+                .find(|chunk| chunk.file_name == path)
+                .unwrap()
+                .base_ref
+        };
+        assert!(base_ref(paths[0]));
+        assert!(!base_ref(paths[1]));
+        assert!(!base_ref(paths[2]));
+
+        let output_dir = dir.join("out");
+        snapshot
+            .extract(&snapshot_path, output_dir.to_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            extracted(&output_dir, &files[0]),
+            "unchanged between the two runs"
+        );
+        assert_eq!(extracted(&output_dir, &files[1]), "the second version");
+        assert_eq!(extracted(&output_dir, &files[2]), "only in the snapshot");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn solid_blocks_extract_every_member() {
+        let dir = temp_dir("solid");
+        let files: Vec<PathBuf> = (0..5)
+            .map(|number| dir.join(format!("small{}.txt", number)))
+            .collect();
+        for (number, path) in files.iter().enumerate() {
+            std::fs::write(
+                path,
+                format!("small file number {}\n", number).repeat(number + 1),
+            )
+            .unwrap();
+        }
+        let paths: Vec<&str> = files.iter().map(|path| path.to_str().unwrap()).collect();
+
+        let archive_path = dir.join("solid.mnbl").to_string_lossy().into_owned();
+        let mut archive = MoonBallArchive::with_config(Config::default());
+        archive.disable_embeddings();
+        archive.enable_solid_mode();
+        archive.add_paths(&paths).unwrap();
+        archive.save_archive(&archive_path).unwrap();
+
+        let chain = archive.open_chain(&archive_path).unwrap();
+        assert_eq!(chain[0].1.solid_blocks.len(), 1);
+        assert!(chain[0].1.chunks.iter().all(|chunk| chunk.solid.is_some()));
+
+        let output_dir = dir.join("out");
+        archive
+            .extract(&archive_path, output_dir.to_str().unwrap())
+            .unwrap();
+        for (number, path) in files.iter().enumerate() {
+            assert_eq!(
+                extracted(&output_dir, path),
+                format!("small file number {}\n", number).repeat(number + 1)
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trained_dictionary_is_stored_and_used_for_extraction() {
+        let dir = temp_dir("dictionary");
+        let sample_dir = dir.join("records");
+        std::fs::create_dir_all(&sample_dir).unwrap();
+        // Binary records, so the balanced scheme picks zstd rather than brotli
+        let record = |number: usize| {
+            let mut record = b"\xf0\x9f\x8c\x95MNREC\xff\xfe\x00\x01".to_vec();
+            record.extend((0..256).map(|byte| (byte as u8).wrapping_mul(number as u8 % 5 + 1)));
+            record.repeat(4)
+        };
+        for number in 0..64 {
+            std::fs::write(sample_dir.join(format!("{}.rec", number)), record(number)).unwrap();
+        }
+        let sample_path = sample_dir.to_str().unwrap();
+
+        let archive_path = dir.join("records.mnbl").to_string_lossy().into_owned();
+        let mut archive = MoonBallArchive::with_config(Config::default());
+        archive.disable_embeddings();
+        archive.train_dictionary(&[sample_path]).unwrap();
+        archive.add_paths(&[sample_path]).unwrap();
+        archive.save_archive(&archive_path).unwrap();
+
+        let chain = archive.open_chain(&archive_path).unwrap();
+        assert_eq!(chain[0].1.dictionaries.len(), 1);
+        assert!(chain[0]
+            .1
+            .chunks
+            .iter()
+            .all(|chunk| chunk.compression_algo == "zstd" && chunk.dictionary_id == Some(1)));
+
+        let output_dir = dir.join("out");
+        archive
+            .extract(&archive_path, output_dir.to_str().unwrap())
+            .unwrap();
+        for number in 0..64 {
+            let path = sample_dir.join(format!("{}.rec", number));
+            let relative = safe_relative_path(path.to_str().unwrap()).unwrap();
+            assert_eq!(
+                std::fs::read(output_dir.join(relative)).unwrap(),
+                record(number)
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_builtin_codec_round_trips() {
+        let config = Config::default();
+        let settings = SchemeSettings::resolve(Scheme::Max, &config);
+        let registry = CodecRegistry::with_builtin(&config);
+        let data = "MoonBall keeps every codec reversible.\n"
+            .repeat(500)
+            .into_bytes();
+        for name in ["zstd", "brotli", "lzma", "lz4", "xz", "zstd-long"] {
+            let codec = registry.by_name(name).unwrap();
+            let params = CodecParams {
+                level: settings.level_for(name, &config),
+                window_log: settings.window_log,
+                dictionary: None,
+                original_size: data.len(),
+            };
+            let compressed = codec.compress(&data, &params).unwrap();
+            assert_eq!(
+                codec.decompress(&compressed, &params).unwrap(),
+                data,
+                "{}",
+                name
+            );
+
+            let mut streamed = Vec::new();
+            codec
+                .decompress_stream(&mut compressed.as_slice(), &mut streamed, &params)
+                .unwrap();
+            assert_eq!(streamed, data, "{} (stream)", name);
+        }
+    }
+
+    #[test]
+    fn codec_registry_keeps_stable_ids() {
+        struct StoreCodec(u8, &'static str);
+
+        impl Codec for StoreCodec {
+            fn id(&self) -> u8 {
+                self.0
+            }
+
+            fn name(&self) -> &str {
+                self.1
+            }
+
+            fn compress(
+                &self,
+                data: &[u8],
+                _params: &CodecParams,
+            ) -> Result<Vec<u8>, MoonBallError> {
+                Ok(data.to_vec())
+            }
+
+            fn decompress(
+                &self,
+                data: &[u8],
+                _params: &CodecParams,
+            ) -> Result<Vec<u8>, MoonBallError> {
+                Ok(data.to_vec())
+            }
+        }
+
+        let mut registry = CodecRegistry::with_builtin(&Config::default());
+        for (id, name) in [
+            (1, "zstd"),
+            (2, "brotli"),
+            (3, "lzma"),
+            (4, "lz4"),
+            (5, "xz"),
+            (6, "zstd-long"),
+        ] {
+            assert_eq!(registry.get(id).unwrap().name(), name);
+            assert_eq!(registry.by_name(name).unwrap().id(), id);
+        }
+        assert!(matches!(
+            registry.get(200),
+            Err(MoonBallError::UnsupportedCodec(_))
+        ));
+
+        assert!(registry.register(Arc::new(StoreCodec(0, "store"))).is_err());
+        assert!(registry.register(Arc::new(StoreCodec(1, "store"))).is_err());
+        assert!(registry
+            .register(Arc::new(StoreCodec(200, "zstd")))
+            .is_err());
+        registry
+            .register(Arc::new(StoreCodec(200, "store")))
+            .unwrap();
+        assert_eq!(registry.get(200).unwrap().name(), "store");
+
+        // Chunks written before codec IDs existed are resolved by name
+        let dir = temp_dir("codec-ids");
+        let archive_path = text_archive(&dir, &["resolved by name"]);
+        let archive = MoonBallArchive::with_config(Config::default());
+        let mut chunk = archive.open_chain(&archive_path).unwrap()[0].1.chunks[0].clone();
+        assert_eq!(
+            registry.for_chunk(&chunk).unwrap().id(),
+            registry.by_name(&chunk.compression_algo).unwrap().id()
+        );
+        chunk.codec_id = 0;
+        assert_eq!(
+            registry.for_chunk(&chunk).unwrap().name(),
+            chunk.compression_algo
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn filters_invert_what_they_apply() {
+        let mut executable = b"\x7fELF".to_vec();
+        for position in 0..400u32 {
+            executable.extend([0xE8, 0x10, 0x00, 0x00, 0x00, 0x90]);
+            executable.extend(position.to_le_bytes());
+        }
+        executable.extend([0xE9, 0x01, 0x02]); // Operand cut off by the end of the chunk
+        let table: Vec<u8> = (0..4096u32)
+            .flat_map(|value| (value * 3).to_le_bytes())
+            .collect();
+        let text = b"first line\r\nsecond line\r\n\r\nlast\r\n".repeat(50);
+
+        let archive = MoonBallArchive::with_config(Config::default());
+        assert_eq!(
+            archive.predict_filters(&executable, "tool"),
+            vec![Filter::Bcj]
+        );
+        assert_eq!(
+            archive.predict_filters(&text, "notes.txt"),
+            vec![Filter::Crlf]
+        );
+        assert!(matches!(
+            archive.predict_filters(&table, "samples.dat")[..],
+            [Filter::Delta { .. }]
+        ));
+        assert!(archive
+            .predict_filters(b"a lone\r in text\n", "notes.txt")
+            .is_empty());
+
+        let cases: Vec<(&[u8], Vec<Filter>)> = vec![
+            (&executable, vec![Filter::Bcj]),
+            (&table, vec![Filter::Delta { distance: 4 }]),
+            (&table, vec![Filter::Delta { distance: 1 }]),
+            (&text, vec![Filter::Crlf]),
+            (&text, vec![Filter::Crlf, Filter::Delta { distance: 2 }]),
+        ];
+        for (data, filters) in cases {
+            let filtered = apply_filters(data, &filters);
+            assert_eq!(invert_filters(filtered, &filters), data, "{:?}", filters);
+        }
+        assert_eq!(
+            apply_filters(&text, &[Filter::Crlf]).len(),
+            text.len() - 200
+        );
+    }
+
+    #[test]
+    fn quantized_vectors_stay_within_error_bounds() {
+        let dimension = 64;
+        let vectors = clustered_vectors(600, dimension);
+        let quantized = |quantization, pq_subvectors| {
+            let storage = EmbeddingStorageConfig {
+                quantization,
+                pq_subvectors,
+            };
+            let (data, quantization, pq) = QuantizedVectors::encode(&vectors, dimension, &storage);
+            let block = EmbeddingBlock {
+                space: 1,
+                quantization,
+                pq,
+                chunks: (0..vectors.len()).collect(),
+                offset: 0,
+                length: data.len() as u64,
+            };
+            QuantizedVectors::from_block(&block, dimension, data).unwrap()
+        };
+        let query = &vectors[7];
+
+        for quantization in [
+            Quantization::F32,
+            Quantization::F16,
+            Quantization::Int8,
+            Quantization::Pq,
+        ] {
+            let stored = quantized(quantization, 16);
+            assert_eq!(stored.quantization, quantization);
+            let scores = stored.scores(query);
+            for (index, vector) in vectors.iter().enumerate() {
+                let largest = vector
+                    .iter()
+                    .fold(0.0f32, |max, value| max.max(value.abs()));
+                // Largest error per component, and for the cosine score
+                let (component_bound, score_bound) = match quantization {
+                    Quantization::F32 => (0.0, 1e-5),
+                    Quantization::F16 => (largest * 2.0f32.powi(-11), 1e-3),
+                    Quantization::Int8 => (largest / 254.0, 0.01),
+                    Quantization::Pq => (0.1, 0.05),
+                };
+                for (original, restored) in vector.iter().zip(stored.decode(index)) {
+                    assert!(
+                        (original - restored).abs() <= component_bound + 1e-6,
+                        "{:?}: {} vs {}",
+                        quantization,
+                        original,
+                        restored
+                    );
+                }
+                let exact = cosine_similarity(query, vector);
+                assert!(
+                    (scores[index] - exact).abs() <= score_bound,
+                    "{:?}: {} vs {}",
+                    quantization,
+                    scores[index],
+                    exact
+                );
+            }
+        }
+
+        // PQ needs subvectors that divide the dimension and falls back to int8 otherwise
+        let fallback = quantized(Quantization::Pq, 10);
+        assert_eq!(fallback.quantization, Quantization::Int8);
+        assert!(fallback.pq.is_none());
+    }
+
+    #[test]
+    fn hnsw_recall_matches_brute_force() {
+        let mut vectors = clustered_vectors(2050, 32);
+        for vector in &mut vectors {
+            let norm = dot_product(vector, vector).sqrt();
+            vector.iter_mut().for_each(|value| *value /= norm);
+        }
+        let queries = vectors.split_off(2000);
+        let config = AnnConfig::default();
+        let built = Hnsw::build(&vectors, &config);
+        let index = AnnIndex {
+            block: 0,
+            m: built.m,
+            entry_point: built.entry_point,
+            max_level: built.max_level,
+            offset: 0,
+            length: 0,
+        };
+        let graph = Hnsw::from_bytes(&index, vectors.len(), &built.to_bytes()).unwrap();
+
+        let k = 10;
+        let mut found = 0;
+        for query in &queries {
+            let mut exact: Vec<usize> = (0..vectors.len()).collect();
+            exact.sort_by(|a, b| {
+                dot_product(query, &vectors[*b]).total_cmp(&dot_product(query, &vectors[*a]))
+            });
+            let approximate: HashSet<usize> = graph
+                .search(&|node| dot_product(query, &vectors[node]), config.ef_search)
+                .iter()
+                .take(k)
+                .map(|candidate| candidate.node)
+                .collect();
+            found += exact[..k]
+                .iter()
+                .filter(|node| approximate.contains(node))
+                .count();
+        }
+        let recall = found as f32 / (queries.len() * k) as f32;
+        assert!(recall >= 0.95, "recall@{} was {}", k, recall);
+    }
+
+    #[test]
+    fn bm25_scores_rare_terms_and_short_chunks_higher() {
+        let mut index = KeywordIndex::default();
+        index.add(10, b"error E0425 in parser");
+        index.add(11, b"parser parser ok");
+        index.add(12, b"\n\t  ");
+        index.add(
+            13,
+            b"the parser reports error E0425 after a long and winding explanation",
+        );
+
+        // idf = ln(1 + (3 - 2 + 0.5) / (2 + 0.5)), length 4 against an average of 6
+        let hits = index.search("e0425", 10);
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].0, 10);
+        assert!((hits[0].1 - 0.544_21).abs() < 1e-4, "{}", hits[0].1);
+        assert_eq!(hits[1].0, 13);
+        assert!(hits[1].1 < hits[0].1);
+
+        // The rare term outweighs two occurrences of a common one
+        let hits = index.search("Parser E0425", 1);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].0, 10);
+        assert!(index.search("missing", 10).is_empty());
+
+        let restored = KeywordIndex::from_bytes(&index.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.search("parser", 10), index.search("parser", 10));
+        index.truncate(1);
+        assert_eq!(index.search("parser", 10).len(), 1);
+    }
+
+    #[test]
+    fn reciprocal_rank_fusion_rewards_agreement() {
+        let fused = reciprocal_rank_fusion(vec![vec!["a", "b", "c"], vec!["c", "d"]], 60.0);
+        let score = |item: &str| fused.iter().find(|(key, _)| *key == item).unwrap().1;
+        assert_eq!(fused.len(), 4);
+        assert_eq!(fused[0].0, "c");
+        assert!((score("c") - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-6);
+        assert!((score("a") - 1.0 / 61.0).abs() < 1e-6);
+        assert!(score("a") > score("b"));
+        assert_eq!(score("b"), score("d")); // Both second in one ranking
+
+        // A larger constant flattens the ranking
+        let steep = reciprocal_rank_fusion(vec![vec!["a", "b"]], 1.0);
+        let flat = reciprocal_rank_fusion(vec![vec!["a", "b"]], 1000.0);
+        assert!(steep[0].1 / steep[1].1 > flat[0].1 / flat[1].1);
+    }
+
+    #[test]
+    fn failures_map_to_their_exit_codes() {
+        let dir = temp_dir("exit-codes");
+        let archive_path = text_archive(&dir, &["exit codes for scripts"]);
+        let original = std::fs::read(&archive_path).unwrap();
+        let archive = MoonBallArchive::with_config(Config::default());
+        let exit_code = |bytes: &[u8]| {
+            let damaged = dir.join("damaged.mnbl");
+            std::fs::write(&damaged, bytes).unwrap();
+            archive
+                .list_files(damaged.to_str().unwrap())
+                .unwrap_err()
+                .exit_code()
+        };
+
+        assert_eq!(
+            archive
+                .list_files(dir.join("missing.mnbl").to_str().unwrap())
+                .unwrap_err()
+                .exit_code(),
+            11
+        );
+        assert_eq!(exit_code(&b"not an archive at all, just text".repeat(4)), 3);
+        let mut newer = original.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(exit_code(&newer), 4);
+        let mut corrupt = original.clone();
+        let inside_index = original.len() - FOOTER_LEN as usize - 2;
+        corrupt[inside_index] ^= 0x01;
+        assert_eq!(exit_code(&corrupt), 5);
+
+        let codes: Vec<i32> = [
+            MoonBallError::Other(String::new()),
+            MoonBallError::Format(String::new()),
+            MoonBallError::UnsupportedVersion {
+                archive: String::new(),
+                version: 2,
+            },
+            MoonBallError::ChecksumMismatch {
+                archive: String::new(),
+                part: String::new(),
+                chunk: None,
+            },
+            MoonBallError::WrongPassword,
+            MoonBallError::Otp(String::new()),
+            MoonBallError::UnsupportedCodec(String::new()),
+            MoonBallError::Embedding(String::new()),
+            MoonBallError::Config(String::new()),
+            MoonBallError::Io(std::io::ErrorKind::NotFound.into()),
+            MoonBallError::Cancelled,
+        ]
+        .iter()
+        .map(MoonBallError::exit_code)
+        .collect();
+        // Matches the table in the README; 2 belongs to clap
+        assert_eq!(codes, vec![1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 130]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn error_policies_skip_abort_or_retry() {
+        let retry = RetryConfig {
+            attempts: 3,
+            initial_delay_ms: 0,
+            ..RetryConfig::default()
+        };
+        let failing_until = |successful_call: u32| {
+            let mut calls = 0;
+            move || -> Result<u32, MoonBallError> {
+                calls += 1;
+                if calls >= successful_call {
+                    Ok(calls)
+                } else {
+                    Err(MoonBallError::Other(format!("call {} failed", calls)))
+                }
+            }
+        };
+
+        let outcome = run_with_policy(ErrorPolicy::Continue, &retry, "a.txt", failing_until(2));
+        assert!(matches!(
+            outcome,
+            Ok(Outcome::Skipped {
+                error: MoonBallError::Other(ref message),
+                attempts: 1
+            }) if message == "call 1 failed"
+        ));
+        let outcome = run_with_policy(ErrorPolicy::Abort, &retry, "a.txt", failing_until(2));
+        assert!(matches!(outcome, Err(MoonBallError::Other(_))));
+        let outcome = run_with_policy(ErrorPolicy::Abort, &retry, "a.txt", failing_until(1));
+        assert!(matches!(
+            outcome,
+            Ok(Outcome::Done {
+                value: 1,
+                attempts: 1
+            })
+        ));
+
+        let outcome = run_with_policy(ErrorPolicy::Retry, &retry, "a.txt", failing_until(3));
+        assert!(matches!(
+            outcome,
+            Ok(Outcome::Done {
+                value: 3,
+                attempts: 3
+            })
+        ));
+        let outcome = run_with_policy(ErrorPolicy::Retry, &retry, "a.txt", failing_until(4));
+        assert!(
+            matches!(outcome, Err(MoonBallError::Other(ref message)) if message == "call 3 failed")
+        );
+
+        let mut calls = 0;
+        let outcome = run_with_policy(ErrorPolicy::Retry, &retry, "a.txt", || -> Result<(), _> {
+            calls += 1;
+            Err(MoonBallError::Cancelled)
+        });
+        assert!(matches!(outcome, Err(MoonBallError::Cancelled)));
+        assert_eq!(calls, 1);
+
+        let backoff = RetryConfig {
+            max_delay_ms: 1500,
+            ..RetryConfig::default()
+        };
+        let delays: Vec<u128> = (1..=4)
+            .map(|retry| backoff.delay(retry).as_millis())
+            .collect();
+        assert_eq!(delays, vec![500, 1000, 1500, 1500]);
+    }
+
+    #[test]
+    fn log_file_rotates_at_max_size_and_keeps_backup_count() {
+        let dir = temp_dir("rotation");
+        let path = dir.join("logs").join("moonball.log");
+        let backup = |number: u32| PathBuf::from(format!("{}.{}", path.display(), number));
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        let lines = |range: std::ops::Range<u32>| {
+            range
+                .map(|number| format!("line {:04}\n", number))
+                .collect::<String>()
+        };
+
+        // Ten 10-byte lines fill each 100-byte file
+        let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+        for number in 0..35 {
+            file.write_line(&format!("line {:04}", number)).unwrap();
+        }
+        assert_eq!(read(&path), lines(30..35));
+        assert_eq!(read(&backup(1)), lines(20..30));
+        assert_eq!(read(&backup(2)), lines(10..20));
+        assert!(!backup(3).exists());
+
+        // Reopening continues from the current size
+        drop(file);
+        let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+        for number in 35..41 {
+            file.write_line(&format!("line {:04}", number)).unwrap();
+        }
+        assert_eq!(read(&path), lines(40..41));
+        assert_eq!(read(&backup(1)), lines(30..40));
+        assert_eq!(read(&backup(2)), lines(20..30));
+
+        // Without backups the file is truncated instead, and an oversized line
+        // still goes into an empty file
+        let path = dir.join("single.log");
+        let mut file = RotatingFile::open(&path, 100, 0).unwrap();
+        let long = "x".repeat(150);
+        file.write_line(&long).unwrap();
+        file.write_line("after").unwrap();
+        assert_eq!(read(&path), "after\n");
+        file.write_line(&long).unwrap();
+        assert_eq!(read(&path), format!("{}\n", long));
+        assert!(!PathBuf::from(format!("{}.1", path.display())).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ollama_chat_sends_context_length_and_returns_message() {
+        let (api_base, server) = stub_server(
+            r#"{"message":{"role":"assistant","content":"It is in [1]."}}"#,
+            1,
+        );
+        let client = chat_client("ollama", api_base, "", 8192);
+
+        let reply = client
+            .chat(&[ChatMessage::new("user", "Where is it?")])
+            .unwrap();
+        assert_eq!(reply, "It is in [1].");
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /api/chat "));
+        let body: serde_json::Value =
+            serde_json::from_str(requests[0].split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["options"]["num_ctx"], 8192);
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["content"], "Where is it?");
+    }
+
+    #[test]
+    fn openai_chat_authorizes_and_returns_first_choice() {
+        let (api_base, server) = stub_server(
+            r#"{"choices":[{"message":{"role":"assistant","content":"See [2]."}}]}"#,
+            1,
+        );
+        let client = chat_client("openai", api_base, "sk-test", 4096);
+
+        let reply = client
+            .chat(&[ChatMessage::new("user", "Which file?")])
+            .unwrap();
+        assert_eq!(reply, "See [2].");
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /chat/completions "));
+        assert!(requests[0]
+            .to_ascii_lowercase()
+            .contains("authorization: bearer sk-test"));
+    }
+
+    #[test]
+    fn chat_without_message_is_an_error() {
+        let (api_base, server) = stub_server(r#"{"choices":[]}"#, 1);
+        let client = chat_client("openai", api_base, "", 4096);
+        assert!(client
+            .chat(&[ChatMessage::new("user", "Anything?")])
+            .is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn prompt_budget_reserves_room_for_the_reply_and_system_message() {
+        let system_tokens = estimate_tokens("You answer questions about archives.");
+        assert_eq!(
+            chat_client("ollama", String::new(), "", 4096).prompt_budget(),
+            4096 - 1024 - system_tokens
+        );
+        // The reply reservation is capped at 2048 tokens
+        assert_eq!(
+            chat_client("ollama", String::new(), "", 32768).prompt_budget(),
+            32768 - 2048 - system_tokens
+        );
+        assert_eq!(
+            chat_client("ollama", String::new(), "", 0).prompt_budget(),
+            0
+        );
+    }
+
+    #[test]
+    fn build_context_numbers_hits_in_rank_order() {
+        let dir = temp_dir("context-order");
+        let archive_path = text_archive(&dir, &["first excerpt", "second excerpt"]);
+        let archive = MoonBallArchive::with_config(Config::default());
+        let chain = archive.open_chain(&archive_path).unwrap();
+        let mut hits = hits_for(&chain[0].1);
+        hits.reverse();
+
+        let (context, sources) = archive.build_context(&chain, &hits, 10_000).unwrap();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(
+            sources
+                .iter()
+                .map(|source| source.number)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(sources[0].file_name, hits[0].file_name);
+        let first = context.find("[1] ").unwrap();
+        let second = context.find("[2] ").unwrap();
+        assert!(first < second);
+        assert!(context[first..second].contains("second excerpt"));
+        assert!(context[second..].contains("first excerpt"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn build_context_truncates_to_the_budget() {
+        let dir = temp_dir("context-budget");
+        let long = "word ".repeat(2000);
+        let archive_path = text_archive(&dir, &[&long, "never reached"]);
+        let archive = MoonBallArchive::with_config(Config::default());
+        let chain = archive.open_chain(&archive_path).unwrap();
+        let hits = hits_for(&chain[0].1);
+
+        let budget = 200;
+        let (context, sources) = archive.build_context(&chain, &hits, budget).unwrap();
+        assert_eq!(sources.len(), 1);
+        assert!(estimate_tokens(&context) <= budget + 2);
+        assert!(!context.contains("never reached"));
+
+        let (context, sources) = archive.build_context(&chain, &hits, 10).unwrap();
+        assert!(sources.is_empty());
+        assert!(context.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn embedding_failures_flag_chunks_instead_of_aborting() {
+        let dir = temp_dir("embedding-failure");
+        let config = Config {
+            preset: "fallback".to_string(),
+            fallback_model: dir.join("no-such-model").to_string_lossy().into_owned(),
+            error_handling: "abort".to_string(),
+            ..Config::default()
+        };
+        let mut archive = MoonBallArchive::with_config(config);
+        let file_path = dir.join("note.txt");
+        std::fs::write(&file_path, "kept without an embedding").unwrap();
+        archive.add_paths(&[file_path.to_str().unwrap()]).unwrap();
+        let archive_path = dir.join("notes.mnbl").to_string_lossy().into_owned();
+        archive.save_archive(&archive_path).unwrap();
+        assert_eq!(archive.report().missing_embeddings, 1);
+
+        let chain = archive.open_chain(&archive_path).unwrap();
+        let chunks = &chain[0].1.chunks;
+        assert!(!chunks.is_empty());
+        assert!(chunks.iter().all(|chunk| chunk.embedding_pending));
+
+        let output_dir = dir.join("out");
+        archive
+            .extract(&archive_path, output_dir.to_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            extracted(&output_dir, &file_path),
+            "kept without an embedding"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn config_layers_apply_in_precedence_order() {
+        let dir = temp_dir("config-layers");
+        let layer = |name: &str, yaml: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, yaml).unwrap();
+            (path, false)
+        };
+        let files = vec![
+            layer(
+                "user.yml",
+                "compression_level: 1\nchunk_size: 1000\nerror_handling: retry\nlogging_level: DEBUG\npreset: speed\n",
+            ),
+            layer(
+                "project.yml",
+                "compression_level: 2\nchunk_size: 2000\nerror_handling: abort\nlogging_level: ERROR\n",
+            ),
+            layer(
+                "flag.yml",
+                "compression_level: 3\nchunk_size: 3000\nerror_handling: continue\n",
+            ),
+            (dir.join("missing.yml"), false),
+        ];
+        let env_vars = vec![
+            ("MOONBALL_COMPRESSION_LEVEL".to_string(), "4".to_string()),
+            ("MOONBALL_CHUNK_SIZE".to_string(), "4000".to_string()),
+        ];
+        let overrides = vec![("compression_level".to_string(), "5".to_string())];
+
+        let report = layer_config(files, env_vars, &overrides);
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        let config = report.config.unwrap();
+        assert_eq!(config.compression_level, 5);
+        assert_eq!(config.chunk_size, 4000);
+        assert_eq!(config.error_handling, "continue");
+        assert_eq!(config.logging_level, "ERROR");
+        assert_eq!(config.preset, "speed");
+        assert_eq!(config.scheme, Config::default().scheme);
+        assert_eq!(report.layers.len(), 7);
+        assert_eq!(report.layers[0], "built-in defaults");
+        assert_eq!(report.layers[6], "command line compression_level");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn config_errors_name_the_field_path() {
+        let dir = temp_dir("config-errors");
+        let path = dir.join("config.yml");
+
+        std::fs::write(&path, "compression_algorithms:\n  zstd:\n    level: high\n").unwrap();
+        let report = layer_config(vec![(path.clone(), true)], Vec::new(), &[]);
+        assert!(report.config.is_none());
+        assert_eq!(report.errors.len(), 1);
+        assert!(
+            report.errors[0].starts_with("compression_algorithms.zstd.level: "),
+            "{}",
+            report.errors[0]
+        );
+
+        std::fs::write(&path, "logging:\n  max_size_mb: 0\n").unwrap();
+        let report = layer_config(vec![(path.clone(), true)], Vec::new(), &[]);
+        assert_eq!(
+            report.errors,
+            vec!["logging.max_size_mb: must be greater than 0".to_string()]
+        );
+
+        let env_vars = vec![("MOONBALL_NO_SUCH__KEY".to_string(), "1".to_string())];
+        let report = layer_config(vec![(dir.join("gone.yml"), true)], env_vars, &[]);
+        assert_eq!(
+            report.errors,
+            vec![format!(
+                "{}: file not found",
+                dir.join("gone.yml").display()
+            )]
+        );
+        assert_eq!(
+            report.warnings,
+            vec!["environment MOONBALL_NO_SUCH__KEY: no such setting".to_string()]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn user_config_lives_under_xdg_config_home() {
+        let dir = temp_dir("xdg");
+        std::env::set_var("XDG_CONFIG_HOME", &dir);
+        assert_eq!(
+            user_config_path().unwrap(),
+            dir.join("moonball").join("config.yml")
+        );
+        std::env::remove_var("XDG_CONFIG_HOME");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn extraction_paths_stay_inside_the_output_directory() {
+        assert_eq!(
+            safe_relative_path("docs/./notes.txt").unwrap(),
+            Path::new("docs/notes.txt")
+        );
+        assert_eq!(
+            safe_relative_path("/etc/passwd").unwrap(),
+            Path::new("etc/passwd")
+        );
+        assert!(safe_relative_path("../outside.txt").is_err());
+        assert!(safe_relative_path("docs/../../outside.txt").is_err());
+        assert!(safe_relative_path("/").is_err());
+    }
+
+    #[test]
+    fn mark_cited_flags_only_referenced_sources() {
+        let sources = (1..=3)
+            .map(|number| Source {
+                number,
+                file_name: format!("file{}.txt", number),
+                chunk_id: 0,
+                byte_range: 0..10,
+                cited: true,
+            })
+            .collect();
+        let sources = mark_cited(sources, "The setting lives in [3], not [12].");
+        assert_eq!(
+            sources
+                .iter()
+                .map(|source| source.cited)
+                .collect::<Vec<_>>(),
+            vec![false, false, true]
+        );
+    }
+}